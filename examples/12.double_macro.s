@@ -0,0 +1,55 @@
+; This program does the same thing as 11.double.s, but it uses a
+; macro to hide the bookkeeping involved in calling DOUBLE.
+
+; A macro is a recipe for a few lines of code. Every time we write
+; 'CALL .SOMEWHERE', the assembler replaces it with the lines
+; between '.macro' and '.endm', using '.SOMEWHERE' wherever the
+; recipe says 'function'. The '.return' label is different for
+; each CALL, so they don't get in each other's way.
+.macro CALL function
+    put  .return  gp2
+    push gp2
+    put  function gp2
+    jump gp2 zero
+    noop .return
+.endm
+
+; Start
+    put 11 gp0
+    put .HEAP gp7
+
+
+; LOAD
+    put   1   gp1 .LOAD
+    sub   gp0 gp1
+    push  ans ; to retain the counter
+    push  ans ; as an argument for DOUBLE
+    CALL  .DOUBLE
+    pop   gp3 ; The result
+    pop   gp0 ; Recover the counter
+    write gp3 gp7
+    put   2   gp1
+    add   gp1 gp7
+    copy  ans gp7
+
+    put .END gp2
+    jump gp2 gp0
+    put .LOAD gp2
+    jump gp2 zero
+
+
+; DOUBLE
+    pop  gp2 .DOUBLE ; Return address
+    pop  gp0 ; Argument
+    put  2 gp1
+    mul  gp0 gp1
+    push ans
+    jump gp2 zero ; Return to caller
+
+
+; END
+    halt .END
+
+
+; Define HEAP Address
+.HEAP
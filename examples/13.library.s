@@ -0,0 +1,22 @@
+; This program multiplies 6 by 7 and prints the answer to the
+; console, using a routine that somebody else already wrote.
+;
+; '.include' pastes another file into this one. The file is looked
+; for next to this one first, and then in any directories given to
+; rpu with '-I'. Have a look in the 'lib' directory to see what
+; else is in there.
+.include "lib/call.s"
+
+; Multiply 6 by 7, leaving the answer in gp0
+    put  6   gp0
+    put  7   gp1
+    mul  gp0 gp1
+    copy ans gp0
+
+; Device 2 is the console
+    put 2 dvc
+    CALL .PRINT_NUMBER
+    halt
+
+; The routine goes after 'halt' so that we don't run it by accident
+.include "lib/print_number.s"
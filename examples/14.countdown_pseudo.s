@@ -0,0 +1,19 @@
+# This is the countdown from 07.countdown.s, written with
+# pseudo-instructions. These aren't real instructions: the
+# assembler turns each one into a few real ones, using gp7 as
+# scratch space. Step through it and watch gp7 change!
+
+; clr writes a zero, so this is the same as 'put 0 dvc'
+clr  dvc
+put  5     gp0
+
+; dec subtracts 1, using gp7 to hold the 1 and leaving the
+; answer in 'ans' as well as gp0
+copy gp0   out   .LOOP
+dec  gp0
+
+; jz jumps if its register is zero, and jmp jumps no matter what
+jz   gp0   .END
+jmp  .LOOP
+
+halt .END
@@ -0,0 +1,39 @@
+# Programs don't have to be one long run of instructions. '.org'
+# moves the assembler to a new address, '.word' fills memory with
+# numbers, and '.space' sets memory aside for later.
+
+.equ COUNT 4
+
+; Add up the numbers in .TABLE. gp0 points at the next number,
+; gp1 counts how many are left, and gp2 holds the total so far.
+put  .TABLE gp0
+put  COUNT  gp1
+clr  gp2
+
+read gp0 gp3  .LOOP
+add  gp2 gp3
+copy ans gp2
+
+; Each number takes up 2 bytes
+put  2   gp3
+add  gp0 gp3
+copy ans gp0
+
+dec  gp1
+jz   gp1 .DONE
+jmp  .LOOP
+
+put  0   dvc  .DONE
+copy gp2 out
+halt
+
+; The table lives well away from the code. Look for it in the
+; memory window!
+.org 128
+.TABLE
+.word 1, 2, 3, 4*10
+
+; The stack grows down from the top of memory. Setting it aside
+; means nothing else can end up there by accident.
+.org 224
+.space 32
@@ -0,0 +1,28 @@
+; This program multiplies 6 by 7 and prints the answer, just like
+; 13.library.s does, but without including the routine it uses.
+; Instead, '.extern' promises that some other object file will
+; define it. Each file is assembled on its own, and then 'rpu link'
+; puts them together:
+;
+;   rpu asm -r examples/16.linking.s
+;   rpu asm -r examples/lib/print_number.s
+;   rpu link -o 16.rpo --map 16.map examples/16.linking.rpo \
+;       examples/lib/print_number.rpo
+;   rpu 16.rpo
+;
+; The routine can be used because it is marked '.global'. Have a
+; look at 16.map to see where it ended up.
+.include "lib/call.s"
+
+.extern .PRINT_NUMBER
+
+; Multiply 6 by 7, leaving the answer in gp0
+    put  6   gp0
+    put  7   gp1
+    mul  gp0 gp1
+    copy ans gp0
+
+; Device 2 is the console
+    put 2 dvc
+    CALL .PRINT_NUMBER
+    halt
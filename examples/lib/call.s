@@ -0,0 +1,27 @@
+; Macros for calling the routines in this library.
+;
+; Include this file at the top of your program, before the first
+; CALL, and include the routines you need at the bottom, after
+; your program halts (otherwise the computer will wander into them
+; by accident).
+;
+; The routines take their arguments in gp0, gp1 and gp2, and leave
+; their answer (if they have one) in gp0. They are free to change
+; any other general purpose register, and CALL and RETURN always
+; change gp7.
+
+; Push the address of the instruction after CALL onto the stack,
+; then jump to FUNCTION.
+.macro CALL function
+    put  .return  gp7
+    push gp7
+    put  function gp7
+    jump gp7 zero
+.return
+.endm
+
+; Jump back to the address on top of the stack.
+.macro RETURN
+    pop  gp7
+    jump gp7 zero
+.endm
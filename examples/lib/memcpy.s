@@ -0,0 +1,24 @@
+; MEMCPY: copy gp2 16-bit words from address gp0 to address gp1
+;
+; Changes gp0 through gp5.
+.include "call.s"
+
+.global .MEMCPY
+.MEMCPY
+    put   1   gp5
+    put   2   gp4
+..LOOP
+    put   ..DONE gp7
+    jump  gp7 gp2
+    read  gp0 gp3
+    write gp3 gp1
+    add   gp0 gp4
+    copy  ans gp0
+    add   gp1 gp4
+    copy  ans gp1
+    sub   gp2 gp5
+    copy  ans gp2
+    put   ..LOOP gp7
+    jump  gp7 zero
+..DONE
+    RETURN
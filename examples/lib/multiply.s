@@ -0,0 +1,22 @@
+; MULTIPLY: gp0 = gp0 * gp1
+;
+; This does the same thing as 'mul', the slow way: by adding gp0 to
+; itself gp1 times. Changes gp1, gp2 and gp5.
+.include "call.s"
+
+.global .MULTIPLY
+.MULTIPLY
+    put  0   gp2
+    put  1   gp5
+..LOOP
+    put  ..DONE gp7
+    jump gp7 gp1
+    add  gp2 gp0
+    copy ans gp2
+    sub  gp1 gp5
+    copy ans gp1
+    put  ..LOOP gp7
+    jump gp7 zero
+..DONE
+    copy gp2 gp0
+    RETURN
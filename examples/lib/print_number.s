@@ -0,0 +1,62 @@
+; PRINT_NUMBER: write gp0 to the current device as decimal digits
+;
+; The machine can't divide, so this finds each digit by subtracting
+; 10 over and over. That makes it slow for big numbers. Changes gp0
+; through gp6.
+.include "call.s"
+
+.global .PRINT_NUMBER
+.PRINT_NUMBER
+    put  1   gp5
+    put  10  gp6
+    put  0   gp2          ; How many digits are on the stack
+..DIGIT
+    put  0   gp1          ; gp0 / 10
+..DIVIDE
+    ; Count gp3 down from gp0 and gp4 down from 10. Whichever
+    ; reaches zero first tells us whether gp0 is less than 10.
+    copy gp0 gp3
+    put  10  gp4
+..COMPARE
+    put  ..REMAINDER gp7
+    jump gp7 gp3
+    sub  gp4 gp5
+    copy ans gp4
+    put  ..SUBTRACT gp7
+    jump gp7 gp4
+    sub  gp3 gp5
+    copy ans gp3
+    put  ..COMPARE gp7
+    jump gp7 zero
+..SUBTRACT
+    ; gp0 is at least 10, so take 10 away and count it
+    sub  gp0 gp6
+    copy ans gp0
+    add  gp1 gp5
+    copy ans gp1
+    put  ..DIVIDE gp7
+    jump gp7 zero
+..REMAINDER
+    ; gp0 is the last digit, and gp1 is all of the digits before it
+    push gp0
+    add  gp2 gp5
+    copy ans gp2
+    copy gp1 gp0
+    put  ..WRITE gp7
+    jump gp7 gp0
+    put  ..DIGIT gp7
+    jump gp7 zero
+..WRITE
+    ; The digits come off the stack in the right order
+    put  ..DONE gp7
+    jump gp7 gp2
+    pop  gp0
+    put  48  gp3          ; The character '0'
+    add  gp0 gp3
+    copy ans out
+    sub  gp2 gp5
+    copy ans gp2
+    put  ..WRITE gp7
+    jump gp7 zero
+..DONE
+    RETURN
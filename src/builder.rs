@@ -0,0 +1,333 @@
+//! Build programs from Rust, without writing out the source code by
+//! hand:
+//!
+//! ```
+//! use rpu::builder::ProgramBuilder;
+//! use rpu::registers::RegisterName::*;
+//!
+//! let program = ProgramBuilder::new()
+//!     .put(3, gp0)
+//!     .label("loop")
+//!     .put(1, gp1)
+//!     .sub(gp0, gp1)
+//!     .copy(ans, gp0)
+//!     .put_label("loop", gp7)
+//!     .jump(gp7, gp0)
+//!     .halt()
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(program.labels[".loop"], 4);
+//! ```
+//!
+//! The builder writes one line of source code per call, which is
+//! then assembled as usual. That's where labels get resolved, and
+//! it means the program's source lines read the same as if they had
+//! been typed in.
+use crate::instructions::Instruction;
+use crate::programs::CompilationError;
+use crate::programs::Program;
+use crate::registers::RegisterName;
+
+#[derive(Debug, Default, Clone)]
+pub struct ProgramBuilder {
+    lines: Vec<String>,
+}
+
+/// Labels may be given with or without their leading `.`
+fn label(name: &str) -> String {
+    match name.starts_with('.') {
+        true => name.to_string(),
+        false => format!(".{name}"),
+    }
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add any instruction
+    pub fn instruction(mut self, instruction: Instruction) -> Self {
+        self.lines.push(instruction.to_string());
+        self
+    }
+
+    /// Mark the address of whatever comes next
+    pub fn label(mut self, name: &str) -> Self {
+        self.lines.push(label(name));
+        self
+    }
+
+    /// `put` the address of a label, which doesn't have to be
+    /// defined yet
+    pub fn put_label(mut self, name: &str, dst: RegisterName) -> Self {
+        self.lines.push(format!("put {} {dst}", label(name)));
+        self
+    }
+
+    /// A 16-bit number in memory, like `.word`
+    pub fn word(mut self, value: u16) -> Self {
+        self.lines.push(format!(".word {value}"));
+        self
+    }
+
+    pub fn halt(self) -> Self {
+        self.instruction(Instruction::halt)
+    }
+
+    pub fn add(self, a: RegisterName, b: RegisterName) -> Self {
+        self.instruction(Instruction::add(a, b))
+    }
+
+    pub fn copy(self, src: RegisterName, dst: RegisterName) -> Self {
+        self.instruction(Instruction::copy(src, dst))
+    }
+
+    pub fn jump(self, dst: RegisterName, cond: RegisterName) -> Self {
+        self.instruction(Instruction::jump(dst, cond))
+    }
+
+    pub fn mul(self, a: RegisterName, b: RegisterName) -> Self {
+        self.instruction(Instruction::mul(a, b))
+    }
+
+    pub fn noop(self) -> Self {
+        self.instruction(Instruction::noop)
+    }
+
+    pub fn put(self, value: u16, dst: RegisterName) -> Self {
+        self.instruction(Instruction::put(value, dst))
+    }
+
+    pub fn pop(self, dst: RegisterName) -> Self {
+        self.instruction(Instruction::pop(dst))
+    }
+
+    pub fn push(self, src: RegisterName) -> Self {
+        self.instruction(Instruction::push(src))
+    }
+
+    pub fn sub(self, a: RegisterName, b: RegisterName) -> Self {
+        self.instruction(Instruction::sub(a, b))
+    }
+
+    pub fn write(self, src: RegisterName, addr: RegisterName) -> Self {
+        self.instruction(Instruction::write(src, addr))
+    }
+
+    pub fn read(self, addr: RegisterName, dst: RegisterName) -> Self {
+        self.instruction(Instruction::read(addr, dst))
+    }
+
+    /// The source code written so far
+    pub fn source(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// Assemble everything, resolving labels along the way
+    pub fn build(&self) -> Result<Program, CompilationError> {
+        Program::try_compile(&self.source())
+    }
+}
+
+/// Assemble a program written right in Rust code. Each statement
+/// ends with a `;`, and a label is a statement of its own:
+///
+/// ```
+/// let program = rpu::rpu_asm! {
+///     put 3 gp0;
+///     .LOOP;
+///     put 1 gp1;
+///     sub gp0 gp1;
+///     copy ans gp0;
+///     put .LOOP gp7;
+///     jump gp7 gp0;
+///     halt;
+/// }.unwrap();
+/// assert_eq!(program.labels[".LOOP"], 4);
+/// ```
+///
+/// Misspelled instructions and registers are caught when the Rust
+/// code is compiled:
+///
+/// ```compile_fail
+/// rpu::rpu_asm! { put 3 gp9; };
+/// ```
+///
+/// ```compile_fail
+/// rpu::rpu_asm! { jmup gp7 gp0; };
+/// ```
+#[macro_export]
+macro_rules! rpu_asm {
+    (@line $builder:expr ;) => {
+        $builder.build()
+    };
+    (@line $builder:expr ; . $label:ident ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line
+            $builder.label(stringify!($label)); $($rest)*
+        )
+    };
+    (@line $builder:expr ; put $value:literal $dst:ident ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line
+            $builder.put($value, $crate::registers::RegisterName::$dst);
+            $($rest)*
+        )
+    };
+    (@line $builder:expr ; put . $label:ident $dst:ident ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line
+            $builder.put_label(
+                stringify!($label),
+                $crate::registers::RegisterName::$dst
+            );
+            $($rest)*
+        )
+    };
+    (@line $builder:expr ; .word $value:literal ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line $builder.word($value); $($rest)*)
+    };
+    (@line $builder:expr ; halt ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line $builder.halt(); $($rest)*)
+    };
+    (@line $builder:expr ; noop ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line $builder.noop(); $($rest)*)
+    };
+    (@line $builder:expr ; pop $dst:ident ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line
+            $builder.pop($crate::registers::RegisterName::$dst); $($rest)*
+        )
+    };
+    (@line $builder:expr ; push $src:ident ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line
+            $builder.push($crate::registers::RegisterName::$src); $($rest)*
+        )
+    };
+    // Everything else takes two registers
+    (@line $builder:expr ; $name:ident $a:ident $b:ident ; $($rest:tt)*) => {
+        $crate::rpu_asm!(@line
+            $builder.$name(
+                $crate::registers::RegisterName::$a,
+                $crate::registers::RegisterName::$b
+            );
+            $($rest)*
+        )
+    };
+    (@line $builder:expr ; $($rest:tt)*) => {
+        compile_error!(concat!(
+            "rpu_asm! doesn't understand this: ",
+            stringify!($($rest)*)
+        ))
+    };
+    ($($body:tt)*) => {
+        $crate::rpu_asm!(@line
+            $crate::builder::ProgramBuilder::new(); $($body)*
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Core;
+    use crate::devices::Device;
+    use crate::registers::RegisterName::*;
+
+    fn run(program: &Program) -> Core {
+        let mut core = Core::new();
+        core.load_program(program).unwrap();
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        while core.is_running() {
+            core.execute_single_instruction(&mut devices).unwrap();
+        }
+        core
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = ProgramBuilder::new()
+            .put(5, gp0)
+            .label("loop")
+            .put(1, gp1)
+            .sub(gp0, gp1)
+            .copy(ans, gp0)
+            .put_label(".done", gp7)
+            .jump(gp7, gp0)
+            .put_label("loop", gp7)
+            .jump(gp7, zero)
+            .label("done")
+            .halt();
+        assert_eq!(builder.source().lines().nth(5), Some("put .done gp7"));
+
+        let program = builder.build().unwrap();
+        assert_eq!(program.labels[".loop"], 4);
+        assert_eq!(program.labels[".done"], 32);
+        assert_eq!(program.source_lines[0], "put 5 gp0");
+
+        let core = run(&program);
+        assert_eq!(core.register_file.gp0, 0);
+    }
+
+    #[test]
+    fn test_same_as_source() {
+        let built = ProgramBuilder::new()
+            .put(7, gp0)
+            .push(gp0)
+            .pop(gp1)
+            .mul(gp0, gp1)
+            .add(ans, gp1)
+            .write(ans, gp2)
+            .read(gp2, gp3)
+            .noop()
+            .halt()
+            .word(9)
+            .build()
+            .unwrap();
+        let source = [
+            "put 7 gp0",
+            "push gp0",
+            "pop gp1",
+            "mul gp0 gp1",
+            "add ans gp1",
+            "write ans gp2",
+            "read gp2 gp3",
+            "noop",
+            "halt",
+            ".word 9",
+        ];
+        let compiled = Program::try_compile(&source.join("\n")).unwrap();
+        assert_eq!(built.segments, compiled.segments);
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let error = ProgramBuilder::new()
+            .put_label("nowhere", gp7)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "<source>:1: the label .nowhere is never defined"
+        );
+    }
+
+    #[test]
+    fn test_macro() {
+        let program = rpu_asm! {
+            put 6 gp0;
+            put 7 gp1;
+            mul gp0 gp1;
+            copy ans gp0;
+            put .END gp7;
+            jump gp7 zero;
+            put 0 gp0;
+            .END;
+            halt;
+            .word 3;
+        }.unwrap();
+        assert_eq!(program.labels[".END"], 28);
+        assert_eq!(program.size(), 34);
+
+        let core = run(&program);
+        assert_eq!(core.register_file.gp0, 42);
+    }
+}
@@ -6,10 +6,11 @@ use crate::programs;
 use crate::instructions;
 use crate::registers;
 use crate::devices::Device;
+use std::fmt;
 
 pub const RAM: usize = 256;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ExecutionError {
     CannotPut(RegisterName),
 
@@ -27,11 +28,81 @@ pub enum ExecutionError {
 
     StackUnderflow,
 
+    /// The bytes starting at this address don't all fit in memory
+    OutOfMemory(u16),
+
     Decode(instructions::DecodeError),
 
     Access(registers::AccessError)
 }
 
+/// Why a register can't be given a value by `put` or `copy`
+fn read_only(name: RegisterName) -> &'static str {
+    match name {
+        RegisterName::pc => {
+            "pc moves by itself, and jump is the way to send it elsewhere"
+        },
+        RegisterName::ans => "ans only holds the answers of add, sub and mul",
+        RegisterName::out => "values are sent to out with copy",
+        _ => "it can't be written to",
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CannotPut(name) => write!(
+                f,
+                "a value can't be put into {name}; {}",
+                read_only(*name)
+            ),
+            Self::CannotAdd(name) => write!(
+                f,
+                "{name} can't be used in arithmetic, because it can't be \
+                read from"
+            ),
+            Self::CannotCpFrom(name) => write!(
+                f,
+                "{name} can't be read from, only written to"
+            ),
+            Self::CannotCpTo(name) => write!(
+                f,
+                "nothing can be copied into {name}; {}",
+                read_only(*name)
+            ),
+            Self::Overflow(x, y) => write!(
+                f,
+                "the answer for {x} and {y} is more than 65535, which \
+                doesn't fit in 16 bits"
+            ),
+            Self::Underflow(x, y) => write!(
+                f,
+                "{x} - {y} is less than 0, and registers can't hold \
+                negative numbers"
+            ),
+            Self::StackOverflow => write!(
+                f,
+                "the stack is full: sp has reached address 0"
+            ),
+            Self::StackUnderflow => write!(
+                f,
+                "there is nothing on the stack to pop"
+            ),
+            Self::OutOfMemory(address) => write!(
+                f,
+                "the bytes from address {address} on go past the end of \
+                memory, which ends at {}",
+                RAM - 1
+            ),
+            Self::Decode(e) => write!(
+                f,
+                "the bytes at pc aren't an instruction: {e}"
+            ),
+            Self::Access(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 impl From<instructions::DecodeError> for ExecutionError {
     fn from(other: instructions::DecodeError) -> Self {
         Self::Decode(other)
@@ -54,23 +125,62 @@ pub struct Core {
     /// they can be displayed on the error console.
     pub tty: String,
 
-    /// Is the CPU running? Becomes `false` when 'halt' is
-    /// issued.
-    pub power: bool
+    /// Whether the CPU is still running, and if not, why not
+    pub state: CoreState,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CoreState {
+    Running,
+
+    /// The program ran `halt`
+    Halted,
+
+    /// An instruction failed. `pc` is left pointing at it, and
+    /// nothing more runs until the core is started over.
+    Faulted(ExecutionError),
 }
 
 #[derive(Debug)]
 pub enum BootError {
     ProgramTooBig(usize),
+
+    /// Two segments (given by their starting addresses) claim the
+    /// same memory
+    Overlap(u16, u16),
+
     Compilation(programs::CompilationError)
 }
 
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ProgramTooBig(end) => write!(
+                f,
+                "the program runs to address {end}, but there are only \
+                {RAM} bytes of memory"
+            ),
+            Self::Overlap(a, b) => write!(
+                f,
+                "the segments at {a} and {b} overlap"
+            ),
+            Self::Compilation(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 impl From<programs::CompilationError> for BootError {
     fn from(other: programs::CompilationError) -> Self {
         Self::Compilation(other)
     }
 }
 
+impl Default for Core {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Core {
     pub fn new()
         -> Self
@@ -78,9 +188,14 @@ impl Core {
         let register_file = RegisterFile::new();
         let memory = [0; RAM];
         let tty = String::new();
-        let power = true;
+        let state = CoreState::Running;
+
+        Self { register_file, memory, tty, state }
+    }
 
-        Self { register_file, memory, tty, power }
+    /// Is the CPU running? Not once it halts or faults.
+    pub fn is_running(&self) -> bool {
+        self.state == CoreState::Running
     }
 
     fn write_tty(&mut self, byte: u16) {
@@ -91,12 +206,16 @@ impl Core {
     pub fn load_program(&mut self, program: &Program)
         -> Result<(),BootError>
     {
-        if program.size() >= RAM {
+        if program.size() > RAM {
             return Err(BootError::ProgramTooBig(program.size()));
         }
-        for (i, byte) in program.bytes().enumerate() {
-            self.memory[i] = byte;
+        if let Some((a, b)) = program.overlap() {
+            return Err(BootError::Overlap(a.start, b.start));
+        }
+        for segment in &program.segments {
+            self.memory[segment.range()].copy_from_slice(&segment.bytes);
         }
+        self.register_file.pc = program.entry;
         Ok(())
     }
 
@@ -108,7 +227,9 @@ impl Core {
         Ok(())
     }
 
-    fn put(&mut self, val: u16, dst: RegisterName)
+    /// Put a value in a register, just as the `put` instruction
+    /// does, so pc and ans can't be changed this way
+    pub fn put(&mut self, val: u16, dst: RegisterName)
         -> Result<(), ExecutionError>
     {
         match dst {
@@ -163,7 +284,7 @@ impl Core {
         let val = match src {
             RegisterName::out => {
                 return Err(
-                    ExecutionError::CannotCpFrom(dst)
+                    ExecutionError::CannotCpFrom(src)
                 );
             },
             _ => self.register_file.read(src)?
@@ -200,7 +321,7 @@ impl Core {
             // Back up to previous address unless that would go
             // negative. This means that `jump 4` and `jump 0`
             // have the same behavior.
-            addr = addr - 4;
+            addr -= 4;
         }
         let cond = self.register_file.read(cond)?;
         if cond == 0 {
@@ -267,13 +388,10 @@ impl Core {
             return Err(ExecutionError::StackUnderflow);
         }
 
-        self.register_file.write(RegisterName::sp, sp + 2)?;
-        let sp = self.register_file.read(RegisterName::sp)?;
-
-        let mut val: [u8; 2] = [0; 2];
-        val[0] = self.memory[sp as usize];
-        val[1] = self.memory[(sp + 1) as usize];
-        let val = u16::from_ne_bytes(val);
+        let top = sp.checked_add(2)
+            .ok_or(ExecutionError::OutOfMemory(sp))?;
+        let val = u16::from_le_bytes(self.bytes_at(top)?);
+        self.register_file.write(RegisterName::sp, top)?;
 
         self.put(val, dst)
     }
@@ -282,7 +400,7 @@ impl Core {
         -> Result<(), ExecutionError>
     {
         let sp = self.register_file.read(RegisterName::sp)?;
-        if sp == 0 {
+        if sp < 2 {
             return Err(ExecutionError::StackOverflow);
         }
 
@@ -293,8 +411,7 @@ impl Core {
             _ => self.register_file.read(src)?
         };
 
-        self.memory[sp as usize] = val.to_ne_bytes()[0];
-        self.memory[(sp + 1) as usize] = val.to_ne_bytes()[1];
+        self.store(sp, &val.to_le_bytes())?;
 
         self.register_file.write(RegisterName::sp, sp - 2)?;
 
@@ -318,9 +435,9 @@ impl Core {
             _ => self.register_file.read(addr)?
         };
 
-        self.memory[addr as usize] = val.to_ne_bytes()[0];
-        self.memory[(addr + 1) as usize] = val.to_ne_bytes()[1];
-        Ok(())
+        // 16-bit values are stored little-endian (low byte first) on
+        // every machine, so memory looks the same wherever rpu runs
+        self.store(addr, &val.to_le_bytes())
     }
 
     fn read(&mut self, addr: RegisterName, dst: RegisterName)
@@ -333,34 +450,71 @@ impl Core {
             _ => self.register_file.read(addr)?
         };
 
-        let mut val: [u8; 2] = [0; 2];
-        val[0] = self.memory[addr as usize];
-        val[1] = self.memory[(addr + 1) as usize];
-        let val = u16::from_ne_bytes(val);
+        let val = u16::from_le_bytes(self.bytes_at(addr)?);
         self.put(val, dst)
     }
 
+    /// The `N` bytes starting at `address`, if they all fit in memory
+    fn bytes_at<const N: usize>(&self, address: u16)
+        -> Result<[u8; N], ExecutionError>
+    {
+        let start = usize::from(address);
+        self.memory.get(start..start + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ExecutionError::OutOfMemory(address))
+    }
+
+    /// Copy `bytes` into memory at `address`, if they all fit
+    fn store(&mut self, address: u16, bytes: &[u8])
+        -> Result<(), ExecutionError>
+    {
+        let start = usize::from(address);
+        self.memory.get_mut(start..start + bytes.len())
+            .ok_or(ExecutionError::OutOfMemory(address))?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+
     pub fn halt(&mut self) -> Result<(), ExecutionError> {
-        self.power = false;
+        self.state = CoreState::Halted;
         Ok(())
     }
 
+    /// Run the instruction at `pc`. An error leaves the core in
+    /// `CoreState::Faulted`, after which this does nothing.
     pub fn execute_single_instruction(
         &mut self,
         devices: &mut [&mut dyn Device],
     ) -> Result<bool, ExecutionError> {
-        if ! self.power {
+        if ! self.is_running() {
             return Ok(false);
         }
+        let result = self.execute(devices);
+        if let Err(e) = &result {
+            self.state = CoreState::Faulted(e.clone());
+        }
+        result
+    }
 
-        let mut instr: [u8; 4] = [0; 4];
+    fn execute(&mut self, devices: &mut [&mut dyn Device])
+        -> Result<bool, ExecutionError>
+    {
         let pc = self.register_file.read(RegisterName::pc)?;
-        instr[0] = self.memory[(pc as usize) + 0];
-        instr[1] = self.memory[(pc as usize) + 1];
-        instr[2] = self.memory[(pc as usize) + 2];
-        instr[3] = self.memory[(pc as usize) + 3];
-        let instr = u32::from_ne_bytes(instr);
+        let instr = u32::from_le_bytes(self.bytes_at(pc)?);
         let instr = Instruction::try_from_u32(instr)?;
+        self.perform(instr, devices)?;
+        let pc = self.register_file.read(RegisterName::pc)?;
+        self.register_file.write(RegisterName::pc, pc + 4)?;
+        Ok(false)
+    }
+
+    /// Do what an instruction says, without fetching it or moving on
+    /// to the next one
+    pub(crate) fn perform(
+        &mut self,
+        instr: Instruction,
+        devices: &mut [&mut dyn Device],
+    ) -> Result<(), ExecutionError> {
         match instr {
             Instruction::halt => self.halt()?,
             Instruction::add(x, y) => self.add(x, y)?,
@@ -375,9 +529,7 @@ impl Core {
             Instruction::write(src, addr) => self.write(src, addr)?,
             Instruction::read(addr, dst) => self.read(addr, dst)?,
         }
-        let pc = self.register_file.read(RegisterName::pc)?;
-        self.register_file.write(RegisterName::pc, pc + 4)?;
-        Ok(false)
+        Ok(())
     }
 }
 
@@ -386,6 +538,20 @@ mod tests {
     use super::*;
     use crate::instructions::InstructionName;
     use crate::devices::Buffer;
+    use crate::programs::Options;
+    use std::path::Path;
+
+    fn run_until_halt(core: &mut Core) {
+        let mut _lcd0 = Buffer(vec![]);
+        let mut _lcd1 = Buffer(vec![]);
+        let mut devices: Vec<&mut dyn Device> = vec![
+            &mut _lcd0,
+            &mut _lcd1
+        ];
+        while core.is_running() {
+            core.execute_single_instruction(&mut devices).unwrap();
+        }
+    }
 
     #[test]
     fn test_tty() {
@@ -467,6 +633,100 @@ mod tests {
         assert_eq!(error, Err(ExecutionError::StackOverflow));
     }
 
+    #[test]
+    fn test_out_of_memory() {
+        let sources = [
+            ("put 255 sp\npush gp0", 255),
+            ("put 253 sp\npop gp0", 255),
+            ("put 255 gp1\nwrite gp0 gp1", 255),
+            ("put 300 gp1\nread gp1 gp2", 300),
+        ];
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        for (source, address) in sources {
+            let mut core = Core::new();
+            core.load_source(source).unwrap();
+            core.execute_single_instruction(&mut devices).unwrap();
+            let error = core.execute_single_instruction(&mut devices);
+            let expected = ExecutionError::OutOfMemory(address);
+            assert_eq!(error, Err(expected.clone()), "{source}");
+            assert_eq!(core.state, CoreState::Faulted(expected));
+        }
+
+        // An instruction has to fit in memory too
+        let mut core = Core::new();
+        core.register_file.pc = 253;
+        let error = core.execute_single_instruction(&mut devices);
+        assert_eq!(error, Err(ExecutionError::OutOfMemory(253)));
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut core = Core::new();
+        core.load_source("put 65535 gp0\nadd gp0 gp0\nhalt").unwrap();
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        core.execute_single_instruction(&mut devices).unwrap();
+        let error = core.execute_single_instruction(&mut devices);
+        let expected = ExecutionError::Overflow(65535, 65535);
+        assert_eq!(error, Err(expected.clone()));
+        assert_eq!(core.state, CoreState::Faulted(expected));
+        assert_eq!(core.register_file.pc, 4);
+
+        // Nothing more runs, so the fault stays put
+        assert_eq!(core.execute_single_instruction(&mut devices), Ok(false));
+        assert_eq!(core.register_file.pc, 4);
+        assert!(!core.is_running());
+        assert_eq!(
+            core.state,
+            CoreState::Faulted(ExecutionError::Overflow(65535, 65535))
+        );
+
+        let mut core = Core::new();
+        core.load_source("halt").unwrap();
+        run_until_halt(&mut core);
+        assert_eq!(core.state, CoreState::Halted);
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let error = ExecutionError::Underflow(3, 5);
+        assert_eq!(
+            error.to_string(),
+            "3 - 5 is less than 0, and registers can't hold negative numbers"
+        );
+        let error = ExecutionError::Decode(
+            instructions::DecodeError::NoSuchInstruction(99)
+        );
+        assert_eq!(
+            error.to_string(),
+            "the bytes at pc aren't an instruction: there is no \
+            instruction number 99"
+        );
+        let error = ExecutionError::Access(
+            registers::AccessError::PseudoRegister(RegisterName::out)
+        );
+        assert_eq!(
+            error.to_string(),
+            "out isn't a real register, so it can't be used here"
+        );
+
+        let error = ExecutionError::CannotPut(RegisterName::out);
+        assert_eq!(
+            error.to_string(),
+            "a value can't be put into out; values are sent to out with copy"
+        );
+        let error = ExecutionError::CannotCpTo(RegisterName::ans);
+        assert_eq!(
+            error.to_string(),
+            "nothing can be copied into ans; ans only holds the answers of \
+            add, sub and mul"
+        );
+        let error = ExecutionError::CannotAdd(RegisterName::out);
+        assert_eq!(
+            error.to_string(),
+            "out can't be used in arithmetic, because it can't be read from"
+        );
+    }
+
     #[test]
     fn test_stack() {
         let mut core = Core::new();
@@ -492,4 +752,125 @@ mod tests {
             .unwrap();
         assert_eq!(gp1, 7);
     }
+
+    #[test]
+    fn test_library() {
+        let mut core = Core::new();
+        let path = Path::new("examples/13.library.s");
+        let program = Program::try_compile_file(path, &Options::default())
+            .unwrap();
+        core.load_program(&program).unwrap();
+
+        run_until_halt(&mut core);
+
+        assert_eq!(&core.tty, "42");
+    }
+
+    #[test]
+    fn test_library_routines() {
+        let mut core = Core::new();
+        let source = [
+            ".include examples/lib/call.s",
+            "put 6 gp0",
+            "put 7 gp1",
+            "CALL .MULTIPLY",
+            "copy gp0 gp6",
+            "put 0 gp0",
+            "put 200 gp1",
+            "put 2 gp2",
+            "CALL .MEMCPY",
+            "halt",
+            ".include examples/lib/multiply.s",
+            ".include examples/lib/memcpy.s",
+        ];
+        let source = source.join("\n");
+        core.load_source(&source).unwrap();
+
+        run_until_halt(&mut core);
+
+        assert_eq!(core.register_file.gp6, 42);
+        assert_eq!(core.memory[200..204], core.memory[0..4]);
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let mut core = Core::new();
+        let path = Path::new("examples/14.countdown_pseudo.s");
+        let program = Program::try_compile_file(path, &Options::default())
+            .unwrap();
+        core.load_program(&program).unwrap();
+
+        run_until_halt(&mut core);
+
+        // jz left the address of .END in gp7 on its way out
+        assert_eq!(core.register_file.gp0, 0);
+        assert_eq!(core.register_file.gp7, 40);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut core = Core::new();
+        let path = Path::new("examples/15.segments.s");
+        let program = Program::try_compile_file(path, &Options::default())
+            .unwrap();
+        core.load_program(&program).unwrap();
+        assert_eq!(core.memory[128], 1);
+
+        run_until_halt(&mut core);
+
+        assert_eq!(core.register_file.gp2, 46);
+    }
+
+    #[test]
+    fn test_overlapping_segments() {
+        let mut core = Core::new();
+        let mut program = Program::try_compile(".org 8\nnoop").unwrap();
+        assert_eq!(program.entry, 8);
+        core.load_program(&program).unwrap();
+        assert_eq!(core.register_file.pc, 8);
+
+        let mut segment = program.segments[0].clone();
+        segment.start = 10;
+        program.segments.push(segment);
+        let error = core.load_program(&program).err().unwrap();
+        assert!(matches!(error, BootError::Overlap(8, 10)));
+    }
+
+    #[test]
+    fn test_full_memory() {
+        let mut core = Core::new();
+        let program = Program::try_compile(".org 252\nnoop").unwrap();
+        assert_eq!(program.size(), RAM);
+        core.load_program(&program).unwrap();
+
+        // Running off the end faults rather than reading past memory
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        core.execute_single_instruction(&mut devices).unwrap();
+        assert_eq!(core.register_file.pc, 256);
+        let error = core.execute_single_instruction(&mut devices);
+        let expected = ExecutionError::OutOfMemory(256);
+        assert_eq!(error, Err(expected.clone()));
+        assert_eq!(core.state, CoreState::Faulted(expected));
+    }
+
+    #[test]
+    fn test_golden_memory() {
+        let mut core = Core::new();
+        core.register_file.gp0 = 0x1234;
+        core.register_file.gp1 = 100;
+        core.write(RegisterName::gp0, RegisterName::gp1).unwrap();
+        assert_eq!(core.memory[100..102], [0x34, 0x12]);
+
+        core.register_file.gp0 = 0xabcd;
+        core.push(RegisterName::gp0).unwrap();
+        assert_eq!(core.memory[254..256], [0xcd, 0xab]);
+
+        core.memory[200..202].copy_from_slice(&[0x78, 0x56]);
+        core.register_file.gp1 = 200;
+        core.read(RegisterName::gp1, RegisterName::gp2).unwrap();
+        assert_eq!(core.register_file.gp2, 0x5678);
+
+        core.pop(RegisterName::gp3).unwrap();
+        assert_eq!(core.register_file.gp3, 0xabcd);
+    }
 }
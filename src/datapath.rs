@@ -0,0 +1,231 @@
+//! Which parts of the machine an instruction uses, and what travels
+//! along the wires between them, so that a picture of the machine can
+//! light them up.
+use crate::core::Core;
+use crate::instructions::Instruction;
+use crate::registers::RegisterName;
+
+/// The parts of the machine
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Unit {
+    Registers,
+
+    /// Adds 4 to pc after every instruction
+    Incrementer,
+
+    /// Lines up the address for `jump`, and takes 4 from it
+    Jump,
+
+    /// Does the arithmetic
+    Alu,
+    Memory,
+
+    /// The LCDs and the console, behind `out`
+    Devices,
+}
+
+/// The wires between the parts
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Wire {
+    /// pc on its way to the incrementer, and back
+    PcOut,
+    PcIn,
+
+    /// The address and condition on their way to the jump unit, and
+    /// the new pc on its way back
+    JumpIn,
+    JumpOut,
+
+    /// The ALU's two inputs, and its answer
+    AluA,
+    AluB,
+    AluOut,
+
+    Address,
+
+    /// Values on their way to memory, and from it
+    MemoryIn,
+    MemoryOut,
+
+    DeviceBus,
+
+    /// A value going from one register to another, or from the
+    /// instruction itself (`put`) into a register
+    Transfer,
+}
+
+/// What an instruction uses, with the values on each wire
+#[derive(Debug, PartialEq, Default)]
+pub struct Datapath {
+    pub units: Vec<Unit>,
+    pub wires: Vec<(Wire, String)>,
+}
+
+impl Datapath {
+    pub fn uses(&self, unit: Unit) -> bool {
+        self.units.contains(&unit)
+    }
+
+    /// What's on a wire, if anything
+    pub fn label(&self, wire: Wire) -> Option<&str> {
+        self.wires.iter()
+            .find(|(w, _)| *w == wire)
+            .map(|(_, label)| label.as_str())
+    }
+
+    fn wire(&mut self, wire: Wire, label: String) {
+        self.wires.push((wire, label));
+    }
+}
+
+impl Core {
+    /// A register's value, with `out` (which can't be read) as 0
+    fn value(&self, name: RegisterName) -> u16 {
+        self.register_file.clone().read(name).unwrap_or_default()
+    }
+
+    /// The number at an address in memory, if there's room for it
+    fn word_at(&self, address: u16) -> Option<u16> {
+        let address = usize::from(address);
+        let bytes = self.memory.get(address..address + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// What `instruction` would use if it ran now
+    pub fn datapath(&self, instruction: Instruction) -> Datapath {
+        let mut datapath = Datapath::default();
+        let operand = |name| format!("{name}: {}", self.value(name));
+        let unknown = || "?".to_string();
+        let mut pc = self.register_file.pc;
+
+        datapath.units.push(Unit::Registers);
+        match instruction {
+            Instruction::halt | Instruction::noop => {},
+            Instruction::add(x, y)
+            | Instruction::sub(x, y)
+            | Instruction::mul(x, y) => {
+                let (a, b) = (self.value(x), self.value(y));
+                let answer = match instruction {
+                    Instruction::add(..) => a.checked_add(b),
+                    Instruction::sub(..) => a.checked_sub(b),
+                    _ => a.checked_mul(b),
+                };
+                datapath.units.push(Unit::Alu);
+                datapath.wire(Wire::AluA, operand(x));
+                datapath.wire(Wire::AluB, operand(y));
+                let answer = answer.map(|answer| answer.to_string())
+                    .unwrap_or_else(|| "doesn't fit".to_string());
+                datapath.wire(Wire::AluOut, format!("ans: {answer}"));
+            },
+            Instruction::copy(src, RegisterName::out) => {
+                let device = match self.register_file.dvc {
+                    0 => "LCD0",
+                    1 => "LCD1",
+                    _ => "console",
+                };
+                datapath.units.push(Unit::Devices);
+                datapath.wire(
+                    Wire::DeviceBus,
+                    format!("{} -> {device}", self.value(src)),
+                );
+            },
+            Instruction::copy(src, dst) => {
+                let value = self.value(src);
+                datapath.wire(Wire::Transfer, format!("{value} -> {dst}"));
+            },
+            Instruction::put(value, dst) => {
+                datapath.wire(Wire::Transfer, format!("{value} -> {dst}"));
+            },
+            Instruction::jump(addr, cond) => {
+                datapath.units.push(Unit::Jump);
+                let condition = self.value(cond);
+                let address = self.value(addr);
+                let label = match cond {
+                    RegisterName::zero => address.to_string(),
+                    _ => format!("{address}, {cond}: {condition}"),
+                };
+                datapath.wire(Wire::JumpIn, label);
+                if condition == 0 {
+                    // See Core::jump
+                    let aligned = address - address % 4;
+                    pc = aligned.saturating_sub(4);
+                    datapath.wire(Wire::JumpOut, format!("pc: {pc}"));
+                }
+            },
+            Instruction::push(src) => {
+                let sp = self.register_file.sp;
+                datapath.units.push(Unit::Memory);
+                datapath.wire(Wire::Address, format!("sp: {sp}"));
+                datapath.wire(Wire::MemoryIn, operand(src));
+            },
+            Instruction::pop(dst) => {
+                let top = self.register_file.sp.saturating_add(2);
+                let value = self.word_at(top)
+                    .map_or_else(unknown, |value| value.to_string());
+                datapath.units.push(Unit::Memory);
+                datapath.wire(Wire::Address, format!("sp+2: {top}"));
+                datapath.wire(Wire::MemoryOut, format!("{value} -> {dst}"));
+            },
+            Instruction::write(src, addr) => {
+                datapath.units.push(Unit::Memory);
+                datapath.wire(Wire::Address, operand(addr));
+                datapath.wire(Wire::MemoryIn, operand(src));
+            },
+            Instruction::read(addr, dst) => {
+                let value = self.word_at(self.value(addr))
+                    .map_or_else(unknown, |value| value.to_string());
+                datapath.units.push(Unit::Memory);
+                datapath.wire(Wire::Address, operand(addr));
+                datapath.wire(Wire::MemoryOut, format!("{value} -> {dst}"));
+            },
+        }
+
+        // Every instruction moves pc on, even one that jumped
+        datapath.units.push(Unit::Incrementer);
+        datapath.wire(Wire::PcOut, format!("pc: {pc}"));
+        datapath.wire(Wire::PcIn, pc.wrapping_add(4).to_string());
+        datapath
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::RegisterName::*;
+
+    #[test]
+    fn test_datapath() {
+        let mut core = Core::new();
+        core.register_file.gp0 = 300;
+        core.register_file.gp1 = 26;
+        core.register_file.pc = 8;
+        core.memory[26] = 7;
+
+        let datapath = core.datapath(Instruction::mul(gp0, gp1));
+        assert!(datapath.uses(Unit::Alu));
+        assert!(!datapath.uses(Unit::Memory));
+        assert_eq!(datapath.label(Wire::AluA), Some("gp0: 300"));
+        assert_eq!(datapath.label(Wire::AluOut), Some("ans: 7800"));
+        assert_eq!(datapath.label(Wire::PcIn), Some("12"));
+
+        let datapath = core.datapath(Instruction::read(gp1, gp2));
+        assert!(datapath.uses(Unit::Memory));
+        assert_eq!(datapath.label(Wire::MemoryOut), Some("7 -> gp2"));
+        assert_eq!(datapath.label(Wire::MemoryIn), None);
+
+        let datapath = core.datapath(Instruction::copy(gp0, out));
+        assert!(datapath.uses(Unit::Devices));
+        assert_eq!(datapath.label(Wire::DeviceBus), Some("300 -> LCD0"));
+
+        // pc is set 4 short of 24, and then moves on to it
+        let datapath = core.datapath(Instruction::jump(gp1, zero));
+        assert_eq!(datapath.label(Wire::JumpOut), Some("pc: 20"));
+        assert_eq!(datapath.label(Wire::PcOut), Some("pc: 20"));
+        assert_eq!(datapath.label(Wire::PcIn), Some("24"));
+
+        let datapath = core.datapath(Instruction::jump(gp1, gp0));
+        assert!(datapath.uses(Unit::Jump));
+        assert_eq!(datapath.label(Wire::JumpOut), None);
+        assert_eq!(datapath.label(Wire::PcIn), Some("12"));
+    }
+}
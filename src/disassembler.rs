@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::instructions::Instruction;
+use crate::programs::Program;
+use crate::programs::SegmentKind;
+
+/// One line of disassembled code
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+
+    /// The bytes this line was decoded from (empty for directives
+    /// like `.org`, which don't take up any memory)
+    pub bytes: Vec<u8>,
+
+    /// Labels that point at `address`
+    pub labels: Vec<String>,
+
+    /// Source code that would assemble back into `bytes`
+    pub text: String,
+
+    /// Labels whose address matches the value in a `put`, which may
+    /// or may not be a coincidence
+    pub comment: Option<String>,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for label in &self.labels {
+            writeln!(f, "{label}")?;
+        }
+        if self.bytes.is_empty() {
+            return write!(f, "{}", self.text);
+        }
+        let bytes: Vec<String> = self.bytes.iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let (text, address) = (&self.text, self.address);
+        write!(f, "    {text:<20} ; {address:>5}: {}", bytes.join(" "))?;
+        if let Some(comment) = &self.comment {
+            write!(f, "  {comment}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks up the labels at an address, sorted so that the output
+/// doesn't change from one run to the next
+struct Symbols {
+    by_address: HashMap<u16, Vec<String>>,
+}
+
+impl Symbols {
+    fn new(labels: &HashMap<String, u16>) -> Self {
+        let mut by_address: HashMap<u16, Vec<String>> = HashMap::new();
+        for (label, address) in labels {
+            by_address.entry(*address).or_default().push(label.to_string());
+        }
+        for labels in by_address.values_mut() {
+            labels.sort();
+        }
+        Self { by_address }
+    }
+
+    fn at(&self, address: u16) -> Vec<String> {
+        self.by_address.get(&address).cloned().unwrap_or_default()
+    }
+}
+
+fn line(address: u16, bytes: &[u8], symbols: &Symbols, text: String)
+    -> Line
+{
+    let labels = symbols.at(address);
+    let bytes = bytes.to_vec();
+    Line { address, bytes, labels, text, comment: None }
+}
+
+/// `.word` lines for bytes that aren't instructions
+fn words(bytes: &[u8], base: u16, symbols: &Symbols) -> Vec<Line> {
+    let mut lines = vec![];
+    for (i, chunk) in bytes.chunks(2).enumerate() {
+        let address = base.wrapping_add(2 * i as u16);
+        let text = match chunk {
+            [low, high] => {
+                format!(".word {}", u16::from_le_bytes([*low, *high]))
+            },
+            _ => format!("; a stray byte: {}", chunk[0]),
+        };
+        lines.push(line(address, chunk, symbols, text));
+    }
+    lines
+}
+
+fn code(bytes: &[u8], base: u16, symbols: &Symbols) -> Vec<Line> {
+    let mut lines = vec![];
+    for (i, chunk) in bytes.chunks(4).enumerate() {
+        let address = base.wrapping_add(4 * i as u16);
+        let Ok(word) = <[u8; 4]>::try_from(chunk) else {
+            lines.extend(words(chunk, address, symbols));
+            continue;
+        };
+        let word = u32::from_le_bytes(word);
+        let Ok(instruction) = Instruction::try_from_u32(word) else {
+            // Not an instruction, so it's probably data
+            lines.extend(words(chunk, address, symbols));
+            continue;
+        };
+        let mut line = line(address, chunk, symbols, instruction.to_string());
+        if let Instruction::put(value, _) = instruction {
+            let labels = symbols.at(value);
+            if !labels.is_empty() {
+                line.comment = Some(labels.join(" "));
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Turn bytes that start at `base` back into source code. Words that
+/// don't decode as instructions come out as `.word`s.
+pub fn disassemble(bytes: &[u8], base: u16) -> Vec<Line> {
+    disassemble_with(bytes, base, &HashMap::new())
+}
+
+/// Like `disassemble`, but with the names of labels filled in
+pub fn disassemble_with(
+    bytes: &[u8],
+    base: u16,
+    labels: &HashMap<String, u16>,
+) -> Vec<Line> {
+    code(bytes, base, &Symbols::new(labels))
+}
+
+impl Program {
+    /// Every segment turned back into source code, with an `.org`
+    /// wherever there is a gap
+    pub fn disassemble(&self) -> Vec<Line> {
+        let symbols = Symbols::new(&self.labels);
+        let mut segments: Vec<_> = self.segments.iter().collect();
+        segments.sort_by_key(|segment| segment.start);
+
+        let mut lines = vec![];
+        let mut address = 0;
+        for segment in segments {
+            if usize::from(segment.start) != address {
+                lines.push(Line {
+                    address: segment.start,
+                    bytes: vec![],
+                    labels: vec![],
+                    text: format!(".org {}", segment.start),
+                    comment: None,
+                });
+            }
+            lines.extend(match segment.kind {
+                SegmentKind::Code => {
+                    code(&segment.bytes, segment.start, &symbols)
+                },
+                SegmentKind::Data => {
+                    words(&segment.bytes, segment.start, &symbols)
+                },
+                SegmentKind::Reserved => {
+                    let text = format!(".space {}", segment.bytes.len());
+                    vec![line(segment.start, &[], &symbols, text)]
+                },
+            });
+            address = segment.end();
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[Line]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let source = [
+            "put 1234 gp0",
+            "add gp0 gp1",
+            "copy ans out",
+            "pop sp",
+            "halt",
+        ];
+        let program = Program::try_compile(&source.join("\n")).unwrap();
+        let bytes: Vec<u8> = program.bytes().collect();
+
+        let lines = disassemble(&bytes, 0);
+        let text: Vec<&str> = lines.iter()
+            .map(|line| line.text.as_str())
+            .collect();
+        assert_eq!(text, source);
+        assert_eq!(lines[1].address, 4);
+        assert_eq!(
+            lines[0].to_string(),
+            "    put 1234 gp0         ;     0: 09 d2 04 00"
+        );
+    }
+
+    #[test]
+    fn test_bad_words() {
+        let bytes = [99, 0, 0, 0, 1, 0, 77, 0, 6, 0, 0, 0, 5];
+        let lines = disassemble(&bytes, 100);
+        let text: Vec<&str> = lines.iter()
+            .map(|line| line.text.as_str())
+            .collect();
+        assert_eq!(text, vec![
+            ".word 99",
+            ".word 0",
+            ".word 1",
+            ".word 77",
+            "noop",
+            "; a stray byte: 5",
+        ]);
+        let addresses: Vec<u16> = lines.iter()
+            .map(|line| line.address)
+            .collect();
+        assert_eq!(addresses, vec![100, 102, 104, 106, 108, 112]);
+    }
+
+    #[test]
+    fn test_program() {
+        let source = [
+            "put .TABLE gp0 .START",
+            "halt",
+            ".org 32",
+            ".TABLE",
+            ".word 7",
+            ".org 224",
+            ".space 32",
+        ];
+        let program = Program::try_compile(&source.join("\n")).unwrap();
+
+        let text = texts(&program.disassemble());
+        assert!(text[0].starts_with(".START\n    put 32 gp0"));
+        assert!(text[0].ends_with(".TABLE"));
+        assert_eq!(text[2], ".org 32");
+        assert!(text[3].starts_with(".TABLE\n    .word 7 "));
+        assert_eq!(text[4], ".org 224");
+        assert_eq!(text[5], ".space 32");
+
+        // ... and it all assembles back into the same thing
+        let lines: Vec<String> = program.disassemble().iter()
+            .map(|line| line.to_string())
+            .collect();
+        let again = Program::try_compile(&lines.join("\n")).unwrap();
+        assert_eq!(again.segments, program.segments);
+        assert_eq!(again.labels, program.labels);
+    }
+}
@@ -0,0 +1,373 @@
+//! Editing the program's source without leaving rpu. The editor
+//! only knows about text; assembling what it saves is up to whoever
+//! asked it to save.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crossterm::event::KeyCode;
+use crossterm::event::KeyEvent;
+use crossterm::event::KeyModifiers;
+use ratatui::Frame;
+use ratatui::layout::Rect;
+use ratatui::style::Style;
+use ratatui::style::Stylize;
+use ratatui::text::Line;
+use ratatui::text::Span;
+use ratatui::widgets::Block;
+use ratatui::widgets::Borders;
+use ratatui::widgets::Paragraph;
+use rpu::programs::CompilationError;
+
+/// How many edits can be undone
+const UNDO_LIMIT: usize = 100;
+
+/// What the editor wants done after a key press
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Nothing,
+    Save,
+    Close,
+}
+
+/// Typing a run of characters is undone all at once, but anything
+/// else is undone one key at a time
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Edit {
+    Typing,
+    Other,
+}
+
+struct Snapshot {
+    lines: Vec<String>,
+    cursor: (usize, usize),
+}
+
+pub struct Editor {
+    pub path: PathBuf,
+    lines: Vec<String>,
+
+    /// The line, and the character within it
+    cursor: (usize, usize),
+
+    /// The first line in view
+    scroll: usize,
+
+    undo: Vec<Snapshot>,
+    last_edit: Option<Edit>,
+
+    /// Has anything changed since the last save?
+    pub modified: bool,
+
+    /// Errors from the last save, by line
+    diagnostics: Vec<(usize, String)>,
+}
+
+/// The byte offset of the `column`th character of `line`
+fn byte_offset(line: &str, column: usize) -> usize {
+    line.char_indices()
+        .nth(column)
+        .map(|(i, _)| i)
+        .unwrap_or(line.len())
+}
+
+impl Editor {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Ok(Self::new(path, &text))
+    }
+
+    fn new(path: &Path, text: &str) -> Self {
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        Self {
+            path: path.to_path_buf(),
+            lines,
+            cursor: (0, 0),
+            scroll: 0,
+            undo: vec![],
+            last_edit: None,
+            modified: false,
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        fs::write(&self.path, self.text())?;
+        self.modified = false;
+        self.last_edit = None;
+        Ok(())
+    }
+
+    /// Put the cursor on a line, such as the one being executed
+    pub fn go_to(&mut self, line: usize) {
+        let row = line.min(self.lines.len() - 1);
+        self.cursor = (row, 0);
+    }
+
+    /// Show an error from assembling the saved file next to the line
+    /// that caused it, if it came from this file at all
+    pub fn diagnose(&mut self, error: Option<&CompilationError>) {
+        self.diagnostics.clear();
+        let Some(CompilationError::At { file, line, error }) = error else {
+            return;
+        };
+        if *file == self.path.display().to_string() {
+            self.diagnostics.push((line - 1, error.to_string()));
+        }
+    }
+
+    fn line_length(&self, row: usize) -> usize {
+        self.lines[row].chars().count()
+    }
+
+    /// Remember how things were before an edit
+    fn checkpoint(&mut self, edit: Edit) {
+        if edit == Edit::Other || self.last_edit != Some(edit) {
+            if self.undo.len() == UNDO_LIMIT {
+                self.undo.remove(0);
+            }
+            self.undo.push(Snapshot {
+                lines: self.lines.clone(),
+                cursor: self.cursor,
+            });
+        }
+        self.last_edit = Some(edit);
+        self.modified = true;
+    }
+
+    pub fn undo(&mut self) {
+        if let Some(snapshot) = self.undo.pop() {
+            self.lines = snapshot.lines;
+            self.cursor = snapshot.cursor;
+            self.last_edit = None;
+            self.modified = true;
+        }
+    }
+
+    /// Diagnostics stay with their lines as lines come and go
+    fn shift_diagnostics(&mut self, after: usize, inserted: bool) {
+        for (line, _) in &mut self.diagnostics {
+            if *line > after {
+                match inserted {
+                    true => *line += 1,
+                    false => *line -= 1,
+                }
+            }
+        }
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.checkpoint(Edit::Typing);
+        let (row, column) = self.cursor;
+        let at = byte_offset(&self.lines[row], column);
+        self.lines[row].insert(at, c);
+        self.cursor.1 += 1;
+    }
+
+    pub fn newline(&mut self) {
+        self.checkpoint(Edit::Other);
+        let (row, column) = self.cursor;
+        let at = byte_offset(&self.lines[row], column);
+        let rest = self.lines[row].split_off(at);
+        self.lines.insert(row + 1, rest);
+        self.shift_diagnostics(row, true);
+        self.cursor = (row + 1, 0);
+    }
+
+    pub fn backspace(&mut self) {
+        let (row, column) = self.cursor;
+        if column > 0 {
+            self.checkpoint(Edit::Other);
+            let at = byte_offset(&self.lines[row], column - 1);
+            self.lines[row].remove(at);
+            self.cursor.1 -= 1;
+        } else if row > 0 {
+            self.checkpoint(Edit::Other);
+            let line = self.lines.remove(row);
+            let column = self.line_length(row - 1);
+            self.lines[row - 1].push_str(&line);
+            self.shift_diagnostics(row - 1, false);
+            self.cursor = (row - 1, column);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        let (row, column) = self.cursor;
+        if column < self.line_length(row) {
+            self.checkpoint(Edit::Other);
+            let at = byte_offset(&self.lines[row], column);
+            self.lines[row].remove(at);
+        } else if row + 1 < self.lines.len() {
+            self.checkpoint(Edit::Other);
+            let line = self.lines.remove(row + 1);
+            self.lines[row].push_str(&line);
+            self.shift_diagnostics(row, false);
+        }
+    }
+
+    /// Move the cursor by some lines and characters, staying inside
+    /// the text
+    fn move_by(&mut self, rows: isize, columns: isize) {
+        let (row, column) = self.cursor;
+        let last = self.lines.len() as isize - 1;
+        let row = (row as isize + rows).clamp(0, last) as usize;
+        let length = self.line_length(row) as isize;
+        let column = (column as isize + columns).clamp(0, length) as usize;
+        self.cursor = (row, column);
+    }
+
+    pub fn key(&mut self, key: KeyEvent) -> Action {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('s') if control => return Action::Save,
+            KeyCode::Char('z') if control => self.undo(),
+            KeyCode::Esc => return Action::Close,
+            KeyCode::Char(c) if !control => self.insert(c),
+            KeyCode::Tab => (0..4).for_each(|_| self.insert(' ')),
+            KeyCode::Enter => self.newline(),
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Up => self.move_by(-1, 0),
+            KeyCode::Down => self.move_by(1, 0),
+            KeyCode::PageUp => self.move_by(-20, 0),
+            KeyCode::PageDown => self.move_by(20, 0),
+            KeyCode::Left => self.move_by(0, -1),
+            KeyCode::Right => self.move_by(0, 1),
+            KeyCode::Home => self.cursor.1 = 0,
+            KeyCode::End => self.cursor.1 = self.line_length(self.cursor.0),
+            _ => {},
+        }
+        Action::Nothing
+    }
+
+    pub fn render(&mut self, area: Rect, frame: &mut Frame, title: &str) {
+        let height = usize::from(area.height.saturating_sub(2)).max(1);
+        let (row, column) = self.cursor;
+        if row < self.scroll {
+            self.scroll = row;
+        } else if row >= self.scroll + height {
+            self.scroll = row + 1 - height;
+        }
+
+        let lines: Vec<Line> = self.lines.iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(height)
+            .map(|(n, text)| {
+                let mut spans = vec![
+                    Span::styled(format!("{:>4} ", n + 1), Style::new().dim()),
+                    Span::raw(text.as_str()),
+                ];
+                let diagnostics = self.diagnostics.iter()
+                    .filter(|(line, _)| *line == n);
+                for (_, message) in diagnostics {
+                    spans.push(Span::styled(
+                        format!("  <- {message}"),
+                        Style::new().bold().red(),
+                    ));
+                }
+                Line::from(spans)
+            })
+            .collect();
+
+        let modified = match self.modified {
+            true => " (modified)",
+            false => "",
+        };
+        let block = Block::new()
+            .title(format!("[{title}{modified}]"))
+            .title_bottom("[Ctrl-S save, Ctrl-Z undo, Esc stop editing]")
+            .borders(Borders::ALL)
+            .border_style(Style::new().yellow());
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+
+        let x = area.x + 1 + 5 + column as u16;
+        let y = area.y + 1 + (row - self.scroll) as u16;
+        let right = area.x + area.width.saturating_sub(2);
+        frame.set_cursor_position((x.min(right), y));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_in(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' => editor.newline(),
+                _ => editor.insert(c),
+            }
+        }
+    }
+
+    #[test]
+    fn test_editing() {
+        let mut editor = Editor::new(Path::new("a.s"), "halt\n");
+        type_in(&mut editor, "put 1 gp0\n");
+        assert_eq!(editor.text(), "put 1 gp0\nhalt\n");
+        assert_eq!(editor.cursor, (1, 0));
+
+        editor.backspace();
+        assert_eq!(editor.text(), "put 1 gp0halt\n");
+        editor.newline();
+        editor.move_by(0, 10);
+        editor.delete();
+        assert_eq!(editor.text(), "put 1 gp0\nhalt\n");
+        editor.backspace();
+        assert_eq!(editor.text(), "put 1 gp0\nhal\n");
+        assert!(editor.modified);
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut editor = Editor::new(Path::new("a.s"), "");
+        type_in(&mut editor, "noop\nhalt");
+        assert_eq!(editor.text(), "noop\nhalt\n");
+
+        // Each run of typing goes at once
+        editor.undo();
+        assert_eq!(editor.text(), "noop\n\n");
+        editor.undo();
+        assert_eq!(editor.text(), "noop\n");
+        assert_eq!(editor.cursor, (0, 4));
+        editor.undo();
+        assert_eq!(editor.text(), "\n");
+        editor.undo();
+        assert_eq!(editor.text(), "\n");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let path = Path::new("a.s");
+        let mut editor = Editor::new(path, "noop\nbogus\nhalt\n");
+        let error = CompilationError::At {
+            file: "a.s".to_string(),
+            line: 2,
+            error: Box::new(CompilationError::UnknownDirective(
+                "bogus".to_string()
+            )),
+        };
+        editor.diagnose(Some(&error));
+        assert_eq!(editor.diagnostics[0].0, 1);
+
+        // The message follows its line around
+        editor.newline();
+        assert_eq!(editor.diagnostics[0].0, 2);
+        editor.backspace();
+        assert_eq!(editor.diagnostics[0].0, 1);
+
+        editor.diagnose(None);
+        assert!(editor.diagnostics.is_empty());
+    }
+}
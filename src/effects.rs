@@ -0,0 +1,193 @@
+//! What an instruction did, found by comparing the machine before
+//! and after it ran.
+use std::fmt;
+use std::ops::Range;
+
+use crate::core::Core;
+use crate::core::RAM;
+use crate::instructions::Instruction;
+use crate::registers::RegisterFile;
+use crate::registers::RegisterName;
+
+/// The parts of the machine that an instruction can change
+pub struct Snapshot {
+    register_file: RegisterFile,
+    memory: [u8; RAM],
+    printed: usize,
+}
+
+/// Every register, with its value
+fn registers(register_file: &RegisterFile) -> [(RegisterName, u16); 12] {
+    [
+        (RegisterName::gp0, register_file.gp0),
+        (RegisterName::gp1, register_file.gp1),
+        (RegisterName::gp2, register_file.gp2),
+        (RegisterName::gp3, register_file.gp3),
+        (RegisterName::gp4, register_file.gp4),
+        (RegisterName::gp5, register_file.gp5),
+        (RegisterName::gp6, register_file.gp6),
+        (RegisterName::gp7, register_file.gp7),
+        (RegisterName::ans, register_file.ans),
+        (RegisterName::dvc, register_file.dvc),
+        (RegisterName::pc, register_file.pc),
+        (RegisterName::sp, register_file.sp),
+    ]
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Effect {
+    /// The instruction, and where it was
+    pub address: u16,
+    pub instruction: Option<Instruction>,
+
+    /// Registers that changed, with their old and new values. `pc`
+    /// is only here when it didn't just move on to the next
+    /// instruction.
+    pub registers: Vec<(RegisterName, u16, u16)>,
+
+    /// Runs of bytes that changed
+    pub memory: Vec<Range<usize>>,
+
+    /// Whatever was written to the console
+    pub printed: String,
+
+    /// The memory after the instruction, to describe it with
+    bytes: Vec<u8>,
+}
+
+impl Core {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            register_file: self.register_file.clone(),
+            memory: self.memory,
+            printed: self.tty.len(),
+        }
+    }
+}
+
+impl Effect {
+    /// What changed between a snapshot and now
+    pub fn since(before: &Snapshot, core: &Core) -> Self {
+        let address = before.register_file.pc;
+        let instruction = before.memory.get(usize::from(address)..)
+            .and_then(|bytes| bytes.get(..4))
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u32::from_le_bytes)
+            .and_then(|word| Instruction::try_from_u32(word).ok());
+
+        let old = registers(&before.register_file);
+        let new = registers(&core.register_file);
+        let registers = old.iter().zip(new)
+            .filter(|((_, old), (_, new))| old != new)
+            .filter(|((name, old), (_, new))| {
+                *name != RegisterName::pc || *new != old.wrapping_add(4)
+            })
+            .map(|((name, old), (_, new))| (*name, *old, new))
+            .collect();
+
+        let mut memory: Vec<Range<usize>> = vec![];
+        for address in 0..RAM {
+            if before.memory[address] == core.memory[address] {
+                continue;
+            }
+            match memory.last_mut() {
+                Some(run) if run.end == address => run.end += 1,
+                _ => memory.push(address..address + 1),
+            }
+        }
+
+        let printed = core.tty.get(before.printed..)
+            .unwrap_or_default()
+            .to_string();
+        let bytes = core.memory.to_vec();
+        Self { address, instruction, registers, memory, printed, bytes }
+    }
+
+    pub fn changed(&self, name: RegisterName) -> bool {
+        self.registers.iter().any(|(register, _, _)| *register == name)
+    }
+
+    pub fn changed_byte(&self, address: usize) -> bool {
+        self.memory.iter().any(|run| run.contains(&address))
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut changes = vec![];
+        for (name, old, new) in &self.registers {
+            changes.push(format!("{name}: {old} -> {new}"));
+        }
+        for run in &self.memory {
+            let bytes = &self.bytes[run.clone()];
+            let last = run.end - 1;
+            changes.push(match bytes {
+                [byte] => format!("mem[{}] = {byte}", run.start),
+                [low, high] => format!(
+                    "mem[{}..{last}] = {}",
+                    run.start,
+                    u16::from_le_bytes([*low, *high])
+                ),
+                _ => {
+                    let bytes: Vec<String> = bytes.iter()
+                        .map(|byte| byte.to_string())
+                        .collect();
+                    format!("mem[{}..{last}] = {}", run.start, bytes.join(" "))
+                },
+            });
+        }
+        if !self.printed.is_empty() {
+            changes.push(format!("printed {:?}", self.printed));
+        }
+        match changes.is_empty() {
+            true => write!(f, "nothing changed"),
+            false => write!(f, "{}", changes.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Device;
+
+    fn effects(source: &[&str]) -> Vec<String> {
+        let mut core = Core::new();
+        core.load_source(&source.join("\n")).unwrap();
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        let mut effects = vec![];
+        while core.is_running() {
+            let before = core.snapshot();
+            core.execute_single_instruction(&mut devices).unwrap();
+            effects.push(Effect::since(&before, &core).to_string());
+        }
+        effects
+    }
+
+    #[test]
+    fn test_effects() {
+        let effects = effects(&[
+            "put 300 gp0",
+            "put 100 gp1",
+            "write gp0 gp1",
+            "put 2 dvc",
+            "put 65 gp0",
+            "copy gp0 out",
+            "put .END gp7",
+            "jump gp7 zero",
+            "put 1 gp0",
+            "halt .END",
+        ]);
+        assert_eq!(effects, vec![
+            "gp0: 0 -> 300",
+            "gp1: 0 -> 100",
+            "mem[100..101] = 300",
+            "dvc: 0 -> 2",
+            "gp0: 300 -> 65",
+            "printed \"A\"",
+            "gp7: 0 -> 36",
+            "pc: 28 -> 36",
+            "nothing changed",
+        ]);
+    }
+}
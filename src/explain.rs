@@ -0,0 +1,336 @@
+//! Saying in plain English what an instruction is about to do, with
+//! the values that are in the registers right now:
+//!
+//! ```
+//! use rpu::explain::explain;
+//! use rpu::instructions::Instruction;
+//! use rpu::registers::RegisterFile;
+//! use rpu::registers::RegisterName::*;
+//!
+//! let mut register_file = RegisterFile::new();
+//! register_file.gp0 = 3;
+//! register_file.gp1 = 4;
+//! assert_eq!(
+//!     explain(Instruction::add(gp0, gp1), &register_file),
+//!     "Add 3 (in gp0) and 4 (in gp1), and put the answer, 7, in ans."
+//! );
+//! ```
+use crate::core::Core;
+use crate::core::RAM;
+use crate::devices::Buffer;
+use crate::devices::Device;
+use crate::instructions::Instruction;
+use crate::registers::RegisterFile;
+use crate::registers::RegisterName;
+
+/// A register's value and where it came from, like `7 (in gp0)`.
+/// `zero` is just 0.
+fn value(register_file: &RegisterFile, name: RegisterName) -> String {
+    match register_file.clone().read(name) {
+        Ok(_) if name == RegisterName::zero => "0".to_string(),
+        Ok(value) => format!("{value} (in {name})"),
+        Err(_) => name.to_string(),
+    }
+}
+
+/// Run the instruction on a copy of the registers, to find out what
+/// it would leave in them, or why it would fail
+fn rehearse(instruction: Instruction, register_file: &RegisterFile)
+    -> Result<RegisterFile, String>
+{
+    let mut core = Core::new();
+    core.register_file = register_file.clone();
+    let mut lcd0 = Buffer(vec![]);
+    let mut lcd1 = Buffer(vec![]);
+    let mut devices: Vec<&mut dyn Device> = vec![&mut lcd0, &mut lcd1];
+    match core.perform(instruction, &mut devices) {
+        Ok(()) => Ok(core.register_file),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// What an instruction sets out to do, before anything it leads to
+fn intent(instruction: Instruction, register_file: &RegisterFile) -> String {
+    let value = |name| value(register_file, name);
+    let RegisterFile { dvc, sp, .. } = *register_file;
+    match instruction {
+        Instruction::halt => "Stop the machine".to_string(),
+        Instruction::noop => "Do nothing".to_string(),
+        Instruction::add(x, y) => {
+            format!("Add {} and {}", value(x), value(y))
+        },
+        Instruction::sub(x, y) => {
+            format!("Subtract {} from {}", value(y), value(x))
+        },
+        Instruction::mul(x, y) => {
+            format!("Multiply {} by {}", value(x), value(y))
+        },
+        Instruction::copy(src, RegisterName::out) => match dvc {
+            0 | 1 => format!(
+                "Show {} on LCD{dvc}, because dvc is {dvc}",
+                value(src)
+            ),
+            _ => format!(
+                "Print {} on the console, because dvc is {dvc}",
+                value(src)
+            ),
+        },
+        Instruction::copy(src, dst) => {
+            format!("Copy {} into {dst}", value(src))
+        },
+        Instruction::put(val, dst) => format!("Put {val} in {dst}"),
+        Instruction::jump(addr, cond) => {
+            let condition = register_file.clone().read(cond);
+            match (cond, condition) {
+                (RegisterName::zero, _) => {
+                    format!("Jump to address {}", value(addr))
+                },
+                (_, Ok(0)) => format!(
+                    "Jump to address {}, because {cond} is 0",
+                    value(addr)
+                ),
+                (_, Ok(n)) => format!(
+                    "Don't jump to address {}, because {cond} is {n}, \
+                    not 0",
+                    value(addr)
+                ),
+                (_, Err(_)) => {
+                    format!("Jump to address {} if {cond} is 0", value(addr))
+                },
+            }
+        },
+        Instruction::push(src) if usize::from(sp) + 1 < RAM => format!(
+            "Push {} onto the stack, at addresses {sp} and {}",
+            value(src),
+            sp + 1
+        ),
+        Instruction::push(src) => {
+            format!("Push {} onto the stack", value(src))
+        },
+        Instruction::pop(dst) if usize::from(sp) + 3 < RAM => format!(
+            "Pop the top of the stack, at addresses {} and {}, into {dst}",
+            sp + 2,
+            sp + 3
+        ),
+        Instruction::pop(dst) => {
+            format!("Pop the top of the stack into {dst}")
+        },
+        Instruction::write(src, addr) => format!(
+            "Write {} to memory at address {}, low byte first",
+            value(src),
+            value(addr)
+        ),
+        Instruction::read(addr, dst) => format!(
+            "Read the number at address {} in memory into {dst}",
+            value(addr)
+        ),
+    }
+}
+
+/// How `Core::jump` gets from the address it's given to the value it
+/// leaves in pc
+fn jump_details(address: u16) -> String {
+    let aligned = address - address % 4;
+    let mut details = String::new();
+    if aligned != address {
+        details += &format!(
+            ". Instructions start at multiples of 4, so {address} is \
+            rounded down to {aligned}"
+        );
+    }
+    match aligned {
+        0 => details += ". pc is set to 0, and then goes up by 4 like it \
+            does after every instruction, so the next instruction is at \
+            4; jumping to 0 and to 4 do the same thing",
+        _ => details += &format!(
+            ". pc is set to {}, 4 less, because it goes up by 4 after \
+            every instruction, so the next one is at {aligned}",
+            aligned - 4
+        ),
+    }
+    details
+}
+
+/// What follows from the instruction, once it has run
+fn outcome(
+    instruction: Instruction,
+    before: &RegisterFile,
+    after: &RegisterFile,
+) -> String {
+    match instruction {
+        Instruction::halt => {
+            ". Nothing more runs until it starts over".to_string()
+        },
+        Instruction::noop => {
+            ", and go on to the next instruction".to_string()
+        },
+        Instruction::add(..) | Instruction::sub(..) | Instruction::mul(..) => {
+            format!(", and put the answer, {}, in ans", after.ans)
+        },
+        Instruction::copy(src, RegisterName::out) if before.dvc > 1 => {
+            let value = before.clone().read(src).unwrap_or_default();
+            let text = String::from_utf16_lossy(&[value]);
+            format!(", which shows up as {text:?}")
+        },
+        Instruction::jump(addr, cond) if before.clone().read(cond) == Ok(0) => {
+            let address = before.clone().read(addr).unwrap_or_default();
+            jump_details(address)
+        },
+        Instruction::jump(..) => {
+            ", so go on to the next instruction".to_string()
+        },
+        Instruction::push(_) => {
+            format!(", and take 2 from sp, making it {}", after.sp)
+        },
+        Instruction::pop(_) => {
+            format!(", and add 2 to sp, making it {}", after.sp)
+        },
+        _ => String::new(),
+    }
+}
+
+/// Describe what `instruction` will do, given the registers as they
+/// are just before it runs
+pub fn explain(instruction: Instruction, register_file: &RegisterFile)
+    -> String
+{
+    let intent = intent(instruction, register_file);
+    match rehearse(instruction, register_file) {
+        Ok(after) => {
+            let outcome = outcome(instruction, register_file, &after);
+            format!("{intent}{outcome}.")
+        },
+        Err(e) => format!("{intent}, but that will fail: {e}."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::RegisterName::*;
+
+    #[test]
+    fn test_explain() {
+        let mut register_file = RegisterFile::new();
+        register_file.gp1 = 24;
+        register_file.gp2 = 0;
+        assert_eq!(
+            explain(Instruction::jump(gp1, gp2), &register_file),
+            "Jump to address 24 (in gp1), because gp2 is 0. pc is set to \
+            20, 4 less, because it goes up by 4 after every instruction, \
+            so the next one is at 24."
+        );
+
+        register_file.gp1 = 26;
+        assert_eq!(
+            explain(Instruction::jump(gp1, zero), &register_file),
+            "Jump to address 26 (in gp1). Instructions start at \
+            multiples of 4, so 26 is rounded down to 24. pc is set to \
+            20, 4 less, because it goes up by 4 after every instruction, \
+            so the next one is at 24."
+        );
+
+        register_file.gp2 = 3;
+        assert_eq!(
+            explain(Instruction::jump(gp1, gp2), &register_file),
+            "Don't jump to address 26 (in gp1), because gp2 is 3, not 0, \
+            so go on to the next instruction."
+        );
+
+        assert!(
+            explain(Instruction::jump(zero, zero), &register_file)
+                .ends_with("jumping to 0 and to 4 do the same thing.")
+        );
+    }
+
+    #[test]
+    fn test_explain_everything_else() {
+        let mut register_file = RegisterFile::new();
+        register_file.gp0 = 65;
+        register_file.gp1 = 100;
+        register_file.dvc = 2;
+        let cases = [
+            (
+                Instruction::sub(gp0, gp1),
+                "Subtract 100 (in gp1) from 65 (in gp0), but that will \
+                fail: 65 - 100 is less than 0, and registers can't hold \
+                negative numbers."
+            ),
+            (
+                Instruction::copy(gp0, out),
+                "Print 65 (in gp0) on the console, because dvc is 2, \
+                which shows up as \"A\"."
+            ),
+            (
+                Instruction::put(7, pc),
+                "Put 7 in pc, but that will fail: a value can't be put \
+                into pc; pc moves by itself, and jump is the way to send \
+                it elsewhere."
+            ),
+            (
+                Instruction::push(gp0),
+                "Push 65 (in gp0) onto the stack, at addresses 254 and \
+                255, and take 2 from sp, making it 252."
+            ),
+            (
+                Instruction::pop(gp0),
+                "Pop the top of the stack into gp0, but that will fail: \
+                there is nothing on the stack to pop."
+            ),
+            (
+                Instruction::write(gp0, gp1),
+                "Write 65 (in gp0) to memory at address 100 (in gp1), low \
+                byte first."
+            ),
+            (
+                Instruction::read(sp, gp2),
+                "Read the number at address 254 (in sp) in memory into \
+                gp2."
+            ),
+            (
+                Instruction::read(out, gp2),
+                "Read the number at address out in memory into gp2, but \
+                that will fail: out can't be read from, only written to."
+            ),
+        ];
+        for (instruction, explanation) in cases {
+            assert_eq!(explain(instruction, &register_file), explanation);
+        }
+    }
+
+    #[test]
+    fn test_explain_stack_edges() {
+        let mut register_file = RegisterFile::new();
+        register_file.gp0 = 65;
+        register_file.sp = 255;
+        assert_eq!(
+            explain(Instruction::push(gp0), &register_file),
+            "Push 65 (in gp0) onto the stack, but that will fail: the \
+            bytes from address 255 on go past the end of memory, which \
+            ends at 255."
+        );
+
+        register_file.sp = 253;
+        assert_eq!(
+            explain(Instruction::pop(gp0), &register_file),
+            "Pop the top of the stack into gp0, but that will fail: the \
+            bytes from address 255 on go past the end of memory, which \
+            ends at 255."
+        );
+
+        register_file.gp1 = 255;
+        assert_eq!(
+            explain(Instruction::write(gp0, gp1), &register_file),
+            "Write 65 (in gp0) to memory at address 255 (in gp1), low byte \
+            first, but that will fail: the bytes from address 255 on go \
+            past the end of memory, which ends at 255."
+        );
+
+        register_file.sp = 252;
+        assert_eq!(
+            explain(Instruction::pop(gp0), &register_file),
+            "Pop the top of the stack, at addresses 254 and 255, into gp0, \
+            and add 2 to sp, making it 254."
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, PartialEq)]
+pub enum EvaluationError {
+    UnexpectedChar(char),
+    UnexpectedEnd,
+    UnclosedParen,
+    InvalidNumber(String),
+    DivideByZero,
+
+    /// The result (or some step along the way) is too big to fit
+    /// anywhere
+    Overflow,
+
+    /// The result is bigger than a register can hold
+    TooBig(i64),
+
+    /// The result is less than zero, which a register can't hold
+    Negative(i64),
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedChar(c) => {
+                write!(f, "didn't expect to find '{c}' in an expression")
+            },
+            Self::UnexpectedEnd => write!(f, "the expression ends early"),
+            Self::UnclosedParen => write!(f, "a '(' is never closed"),
+            Self::InvalidNumber(text) => {
+                write!(f, "{text} is not a valid number")
+            },
+            Self::DivideByZero => write!(f, "can't divide by zero"),
+            Self::Overflow => write!(f, "the expression overflows"),
+            Self::TooBig(value) => write!(
+                f,
+                "{value} is too big (registers only go up to {})",
+                u16::MAX
+            ),
+            Self::Negative(value) => write!(
+                f,
+                "{value} is negative (registers can't go below zero)"
+            ),
+        }
+    }
+}
+
+/// Labels (`.LOOP`) and constants (`SIZE`) are made of these
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+/// A recursive descent parser that evaluates as it goes. Names are
+/// handed to `lookup`, which decides what they are worth (or what
+/// error to report if they're worth nothing at all).
+struct Evaluator<'t, F> {
+    chars: Peekable<Chars<'t>>,
+    lookup: F,
+}
+
+impl<'t, F, E> Evaluator<'t, F>
+where
+    F: FnMut(&str) -> Result<i64, E>,
+    E: From<EvaluationError>,
+{
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    /// comparison := expression (op expression)?, where op is one of
+    /// `==`, `!=`, `<`, `<=`, `>` or `>=`. Comes out as 1 if true and
+    /// 0 if false.
+    fn comparison(&mut self) -> Result<i64, E> {
+        let lhs = self.expression()?;
+        let op = match self.peek() {
+            Some(c @ ('=' | '!' | '<' | '>')) => c,
+            _ => return Ok(lhs),
+        };
+        self.chars.next();
+        let equals = self.chars.next_if_eq(&'=').is_some();
+        if !equals && (op == '=' || op == '!') {
+            return Err(EvaluationError::UnexpectedChar(op).into());
+        }
+        let rhs = self.expression()?;
+        let result = match (op, equals) {
+            ('=', _) => lhs == rhs,
+            ('!', _) => lhs != rhs,
+            ('<', false) => lhs < rhs,
+            ('<', true) => lhs <= rhs,
+            ('>', false) => lhs > rhs,
+            _ => lhs >= rhs,
+        };
+        Ok(result.into())
+    }
+
+    /// expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<i64, E> {
+        let mut value = self.term()?;
+        loop {
+            let result = match self.peek() {
+                Some('+') => {
+                    self.chars.next();
+                    value.checked_add(self.term()?)
+                },
+                Some('-') => {
+                    self.chars.next();
+                    value.checked_sub(self.term()?)
+                },
+                _ => return Ok(value),
+            };
+            value = result.ok_or(EvaluationError::Overflow)?;
+        }
+    }
+
+    /// term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<i64, E> {
+        let mut value = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(c @ ('*' | '/' | '%')) => c,
+                _ => return Ok(value),
+            };
+            self.chars.next();
+            let rhs = self.unary()?;
+            if op != '*' && rhs == 0 {
+                return Err(EvaluationError::DivideByZero.into());
+            }
+            let result = match op {
+                '*' => value.checked_mul(rhs),
+                '/' => value.checked_div(rhs),
+                _ => value.checked_rem(rhs),
+            };
+            value = result.ok_or(EvaluationError::Overflow)?;
+        }
+    }
+
+    /// unary := '-' unary | primary
+    fn unary(&mut self) -> Result<i64, E> {
+        if self.peek() == Some('-') {
+            self.chars.next();
+            let value = self.unary()?;
+            return Ok(value.checked_neg().ok_or(EvaluationError::Overflow)?);
+        }
+        self.primary()
+    }
+
+    /// primary := number | name | '(' expression ')'
+    fn primary(&mut self) -> Result<i64, E> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let value = self.comparison()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(value)
+                    },
+                    _ => Err(EvaluationError::UnclosedParen.into()),
+                }
+            },
+            Some(c) if is_name_char(c) => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.next_if(|c| is_name_char(*c)) {
+                    word.push(c);
+                }
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    Ok(number(&word)?)
+                } else {
+                    (self.lookup)(&word)
+                }
+            },
+            Some(c) => Err(EvaluationError::UnexpectedChar(c).into()),
+            None => Err(EvaluationError::UnexpectedEnd.into()),
+        }
+    }
+}
+
+/// Decimal, or hexadecimal with a leading `0x`
+fn number(word: &str) -> Result<i64, EvaluationError> {
+    let invalid = || EvaluationError::InvalidNumber(word.to_string());
+    match word.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).map_err(|_| invalid()),
+        None => word.parse().map_err(|_| invalid()),
+    }
+}
+
+/// Evaluate an expression like `(.END-.START)/4+SIZE`, which must
+/// come out somewhere between 0 and 65535.
+pub fn evaluate<F, E>(text: &str, lookup: F) -> Result<u16, E>
+where
+    F: FnMut(&str) -> Result<i64, E>,
+    E: From<EvaluationError>,
+{
+    let mut evaluator = Evaluator { chars: text.chars().peekable(), lookup };
+    let value = evaluator.comparison()?;
+    if let Some(c) = evaluator.peek() {
+        return Err(EvaluationError::UnexpectedChar(c).into());
+    }
+    if value < 0 {
+        return Err(EvaluationError::Negative(value).into());
+    }
+    let value = u16::try_from(value)
+        .map_err(|_| EvaluationError::TooBig(value))?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<u16, EvaluationError> {
+        evaluate(text, |name| match name {
+            ".START" => Ok(8),
+            ".END" => Ok(40),
+            "SIZE" => Ok(3),
+            _ => Err(EvaluationError::InvalidNumber(name.to_string())),
+        })
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let pairs = vec![
+            ("7", 7),
+            ("0x1f", 31),
+            ("1+2*3", 7),
+            ("(1+2)*3", 9),
+            ("10-4-3", 3),
+            ("17/5", 3),
+            ("17%5", 2),
+            ("-2+5", 3),
+            ("2 * ( 3 + 4 )", 14),
+            ("1+1 == 2", 1),
+            ("3 != 3", 0),
+            ("2 < 3", 1),
+            ("3 <= 3", 1),
+            ("2 > 3", 0),
+            ("(4 >= 5) + 1", 1),
+        ];
+        for (text, expected) in pairs {
+            assert_eq!(eval(text), Ok(expected), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(eval(".START+4"), Ok(12));
+        assert_eq!(eval(".END-.START"), Ok(32));
+        assert_eq!(eval("2*SIZE"), Ok(6));
+        assert_eq!(eval("(.END-.START)/4"), Ok(8));
+    }
+
+    #[test]
+    fn test_errors() {
+        let pairs = vec![
+            ("1-2", EvaluationError::Negative(-1)),
+            ("65535+1", EvaluationError::TooBig(65536)),
+            ("4/0", EvaluationError::DivideByZero),
+            ("(1+2", EvaluationError::UnclosedParen),
+            ("1+", EvaluationError::UnexpectedEnd),
+            ("1 2", EvaluationError::UnexpectedChar('2')),
+            ("1$", EvaluationError::UnexpectedChar('$')),
+            ("1 = 1", EvaluationError::UnexpectedChar('=')),
+            ("1 < 2 < 3", EvaluationError::UnexpectedChar('<')),
+            ("12ab", EvaluationError::InvalidNumber("12ab".to_string())),
+            (
+                "9223372036854775807*2",
+                EvaluationError::Overflow
+            ),
+        ];
+        for (text, expected) in pairs {
+            assert_eq!(eval(text), Err(expected), "{text}");
+        }
+    }
+}
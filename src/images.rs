@@ -0,0 +1,393 @@
+//! Memory images that other tools can read: Intel HEX and raw binary.
+//!
+//! An Intel HEX file is made of lines ("records") like this one,
+//! every field in hexadecimal:
+//!
+//! ```text
+//! :04 0000 00 09070000 EC
+//!  |   |   |     |     checksum: the sum of every byte, negated
+//!  |   |   |     data
+//!  |   |   type: 00 is data, 01 is the end of the file
+//!  |   address
+//!  length of the data
+//! ```
+//!
+//! (without the spaces). A raw binary file is just the bytes, which
+//! start at whatever address you say they do.
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::core::Core;
+use crate::core::RAM;
+use crate::programs::Program;
+use crate::programs::Segment;
+use crate::programs::SegmentKind;
+use crate::programs::SourceFile;
+
+/// How many bytes go in each data record
+const RECORD_SIZE: usize = 16;
+
+const DATA: u8 = 0;
+const END: u8 = 1;
+const SEGMENT_ADDRESS: u8 = 2;
+const START_SEGMENT: u8 = 3;
+const LINEAR_ADDRESS: u8 = 4;
+const START_LINEAR: u8 = 5;
+
+/// Line numbers start at 1
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+    MissingColon(usize),
+    InvalidHex(usize),
+    WrongLength(usize),
+    BadChecksum { line: usize, expected: u8, found: u8 },
+    UnsupportedRecord { line: usize, kind: u8 },
+    OutOfRange { line: usize, address: usize },
+    AfterEnd(usize),
+    MissingEnd,
+
+    /// `size` bytes starting at `base` won't fit in memory
+    TooBig { base: usize, size: usize },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingColon(line) => write!(
+                f,
+                "line {line}: records start with ':'"
+            ),
+            Self::InvalidHex(line) => write!(
+                f,
+                "line {line}: not a valid hexadecimal number"
+            ),
+            Self::WrongLength(line) => write!(
+                f,
+                "line {line}: the record is not as long as it says it is"
+            ),
+            Self::BadChecksum { line, expected, found } => write!(
+                f,
+                "line {line}: the checksum should be {expected:02X}, \
+                but it is {found:02X}"
+            ),
+            Self::UnsupportedRecord { line, kind } => write!(
+                f,
+                "line {line}: there is no such record type as {kind:02X}"
+            ),
+            Self::OutOfRange { line, address } => write!(
+                f,
+                "line {line}: address {address} is past the end of the \
+                {RAM} bytes of memory"
+            ),
+            Self::AfterEnd(line) => write!(
+                f,
+                "line {line}: there are records after the end record"
+            ),
+            Self::MissingEnd => write!(f, "there is no end record"),
+            Self::TooBig { base, size } => write!(
+                f,
+                "{size} bytes starting at {base} won't fit in the {RAM} \
+                bytes of memory"
+            ),
+        }
+    }
+}
+
+fn check_range(base: usize, size: usize) -> Result<(), ImageError> {
+    match base + size > RAM {
+        true => Err(ImageError::TooBig { base, size }),
+        false => Ok(()),
+    }
+}
+
+fn record(hex: &mut String, kind: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(sum.wrapping_neg());
+
+    hex.push(':');
+    for byte in bytes {
+        write!(hex, "{byte:02X}").unwrap();
+    }
+    hex.push('\n');
+}
+
+/// Data records for `bytes`, which start at `base`
+fn data_records(hex: &mut String, bytes: &[u8], base: u16) {
+    for (i, chunk) in bytes.chunks(RECORD_SIZE).enumerate() {
+        let address = base + (i * RECORD_SIZE) as u16;
+        record(hex, DATA, address, chunk);
+    }
+}
+
+/// `bytes` as an Intel HEX file
+pub fn to_hex(bytes: &[u8], base: u16) -> String {
+    let mut hex = String::new();
+    data_records(&mut hex, bytes, base);
+    record(&mut hex, END, 0, &[]);
+    hex
+}
+
+/// Read an Intel HEX file into runs of bytes. When two records
+/// write to the same address, the later one wins.
+pub fn read_hex(text: &str) -> Result<Vec<Segment>, ImageError> {
+    let mut image: [Option<u8>; RAM] = [None; RAM];
+    let mut ended = false;
+    let mut upper = 0;
+    for (i, text) in text.lines().enumerate() {
+        let line = i + 1;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if ended {
+            return Err(ImageError::AfterEnd(line));
+        }
+        let digits = text.strip_prefix(':')
+            .ok_or(ImageError::MissingColon(line))?;
+        if digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(ImageError::InvalidHex(line));
+        }
+        let bytes = (0..digits.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| ImageError::InvalidHex(line))?;
+
+        // length, address (2 bytes), type, data, checksum
+        let Some((&found, record)) = bytes.split_last() else {
+            return Err(ImageError::WrongLength(line));
+        };
+        if record.len() < 4 || record.len() != 4 + usize::from(record[0]) {
+            return Err(ImageError::WrongLength(line));
+        }
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let expected = sum.wrapping_neg();
+        if found != expected {
+            return Err(ImageError::BadChecksum { line, expected, found });
+        }
+
+        let address = usize::from(u16::from_be_bytes([record[1], record[2]]));
+        let data = &record[4..];
+        let value = || match data {
+            [high, low] => Ok(usize::from(u16::from_be_bytes([*high, *low]))),
+            _ => Err(ImageError::WrongLength(line)),
+        };
+        match record[3] {
+            DATA => {
+                for (offset, byte) in data.iter().enumerate() {
+                    let address = upper + address + offset;
+                    if address >= RAM {
+                        return Err(ImageError::OutOfRange { line, address });
+                    }
+                    image[address] = Some(*byte);
+                }
+            },
+            END => ended = true,
+            SEGMENT_ADDRESS => upper = value()? << 4,
+            LINEAR_ADDRESS => upper = value()? << 16,
+            // Where to start running, on an x86. Doesn't apply here.
+            START_SEGMENT | START_LINEAR => {},
+            kind => return Err(ImageError::UnsupportedRecord { line, kind }),
+        }
+    }
+    if !ended {
+        return Err(ImageError::MissingEnd);
+    }
+
+    let mut segments: Vec<Segment> = vec![];
+    for (address, byte) in image.iter().enumerate() {
+        let Some(byte) = byte else {
+            continue;
+        };
+        match segments.last_mut() {
+            Some(last) if last.end() == address => last.bytes.push(*byte),
+            _ => segments.push(Segment {
+                kind: SegmentKind::Code,
+                start: address as u16,
+                bytes: vec![*byte],
+            }),
+        }
+    }
+    Ok(segments)
+}
+
+/// A raw binary file that starts at `base`, as a single segment
+pub fn read_bin(bytes: &[u8], base: u16) -> Result<Segment, ImageError> {
+    check_range(base.into(), bytes.len())?;
+    let kind = SegmentKind::Code;
+    Ok(Segment { kind, start: base, bytes: bytes.to_vec() })
+}
+
+impl Program {
+    /// Every segment as an Intel HEX file. Reserved space is
+    /// included, so the file says exactly what memory will hold.
+    pub fn to_hex(&self) -> String {
+        let mut hex = String::new();
+        for segment in &self.segments {
+            data_records(&mut hex, &segment.bytes, segment.start);
+        }
+        record(&mut hex, END, 0, &[]);
+        hex
+    }
+
+    /// Memory from address 0 to the end of the program
+    pub fn to_bin(&self) -> Vec<u8> {
+        self.bytes().collect()
+    }
+
+    /// A program made from a memory image, which has no source code.
+    /// The code window shows its disassembly instead.
+    pub fn from_image(name: &str, segments: Vec<Segment>) -> Self {
+        let entry = segments.first().map_or(0, |segment| segment.start);
+        let mut program = Self {
+            segments,
+            entry,
+            ..Self::default()
+        };
+        for line in program.disassemble() {
+            if !line.bytes.is_empty() {
+                let index = program.source_lines.len();
+                program.source_addrs.insert(line.address, index);
+            }
+            program.source_lines.push(line.to_string());
+        }
+        let lines = 0..program.source_lines.len();
+        program.files.push(SourceFile { name: name.to_string(), lines });
+        program
+    }
+}
+
+impl Core {
+    /// Part of memory as an Intel HEX file
+    pub fn to_hex(&self, range: Range<usize>) -> Result<String, ImageError> {
+        check_range(range.start, range.len())?;
+        Ok(to_hex(&self.memory[range.clone()], range.start as u16))
+    }
+
+    /// Part of memory, byte for byte
+    pub fn to_bin(&self, range: Range<usize>) -> Result<Vec<u8>, ImageError> {
+        check_range(range.start, range.len())?;
+        Ok(self.memory[range].to_vec())
+    }
+
+    /// Copy an Intel HEX file into memory. Nothing is copied unless
+    /// the whole file is valid.
+    pub fn load_hex(&mut self, text: &str) -> Result<(), ImageError> {
+        for segment in read_hex(text)? {
+            self.memory[segment.range()].copy_from_slice(&segment.bytes);
+        }
+        Ok(())
+    }
+
+    /// Copy a raw binary file into memory, starting at `base`
+    pub fn load_bin(&mut self, bytes: &[u8], base: u16)
+        -> Result<(), ImageError>
+    {
+        let segment = read_bin(bytes, base)?;
+        self.memory[segment.range()].copy_from_slice(&segment.bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Program {
+        let source = [
+            "put 7 gp0",
+            "halt",
+            ".org 32",
+            ".word 1, 2",
+        ];
+        Program::try_compile(&source.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn test_golden_hex() {
+        let hex = program().to_hex();
+        assert_eq!(hex, [
+            ":080000000907000000000000E8",
+            ":0400200001000200D9",
+            ":00000001FF",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = program();
+        let mut core = Core::new();
+        core.load_hex(&program.to_hex()).unwrap();
+        assert_eq!(core.memory[..program.size()], program.to_bin());
+
+        let hex = core.to_hex(0..40).unwrap();
+        let mut copy = Core::new();
+        copy.load_hex(&hex).unwrap();
+        assert_eq!(copy.memory, core.memory);
+
+        let bin = core.to_bin(32..36).unwrap();
+        assert_eq!(bin, [1, 0, 2, 0]);
+        copy.load_bin(&bin, 200).unwrap();
+        assert_eq!(copy.memory[200..204], [1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = [
+            ("0000000001FF", ImageError::MissingColon(1)),
+            (":0000000G01FF", ImageError::InvalidHex(1)),
+            (":0200000001FF", ImageError::WrongLength(1)),
+            (
+                ":00000001FE",
+                ImageError::BadChecksum {
+                    line: 1,
+                    expected: 0xFF,
+                    found: 0xFE,
+                },
+            ),
+            (
+                ":00000007F9",
+                ImageError::UnsupportedRecord { line: 1, kind: 7 },
+            ),
+            (
+                ":0200FF000102FC\n:00000001FF",
+                ImageError::OutOfRange { line: 1, address: 256 },
+            ),
+            (
+                ":020000040001F9\n:0100000001FE\n:00000001FF",
+                ImageError::OutOfRange { line: 2, address: 65536 },
+            ),
+            (":00000001FF\n:00000001FF", ImageError::AfterEnd(2)),
+            (":0100000001FE", ImageError::MissingEnd),
+        ];
+        for (text, expected) in errors {
+            let mut core = Core::new();
+            assert_eq!(core.load_hex(text), Err(expected), "{text}");
+            assert_eq!(core.memory, [0; RAM]);
+        }
+
+        let mut core = Core::new();
+        let error = core.load_bin(&[1, 2, 3], 254).err();
+        assert_eq!(error, Some(ImageError::TooBig { base: 254, size: 3 }));
+        let error = core.to_hex(200..300).err();
+        assert_eq!(error, Some(ImageError::TooBig { base: 200, size: 100 }));
+    }
+
+    #[test]
+    fn test_from_image() {
+        let segments = read_hex(&program().to_hex()).unwrap();
+        let program = Program::from_image("program.hex", segments);
+        assert_eq!(program.entry, 0);
+        assert_eq!(program.files[0].name, "program.hex");
+        let line = program.source_addrs[&4];
+        assert!(program.source_lines[line].starts_with("    halt "));
+        let mut core = Core::new();
+        core.load_program(&program).unwrap();
+        assert_eq!(core.memory[32..36], [1, 0, 2, 0]);
+    }
+}
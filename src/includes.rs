@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::programs::CompilationError;
+use crate::programs::Options;
+use crate::programs::SourceFile;
+use crate::programs::SourceLine;
+
+/// Reads source files for the assembler. Every file's lines are
+/// appended to `source_lines` the first time it is included, so that
+/// a single line number identifies both a file and a line within it.
+/// An `.include` is only followed when the assembler gets to it, so
+/// one that `.if` leaves out is never read.
+pub struct Loader<'o> {
+    options: &'o Options,
+    pub files: Vec<SourceFile>,
+    pub source_lines: Vec<String>,
+
+    /// Where each of `files` came from (if it came from a file at
+    /// all), and which of them included it
+    origins: Vec<Origin>,
+
+    /// Files that have already been read. Including one of these
+    /// again does nothing.
+    included: HashSet<PathBuf>,
+}
+
+struct Origin {
+    path: Option<PathBuf>,
+    canonical: Option<PathBuf>,
+    parent: Option<usize>,
+}
+
+/// `.include "lib/call.s"` and `.include lib/call.s` both work
+fn unquote(name: &str) -> &str {
+    name.strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .unwrap_or(name)
+}
+
+impl<'o> Loader<'o> {
+    pub fn new(options: &'o Options) -> Self {
+        Self {
+            options,
+            files: vec![],
+            source_lines: vec![],
+            origins: vec![],
+            included: HashSet::new(),
+        }
+    }
+
+    /// Look next to the including file first, then in each of the
+    /// include paths in order
+    fn resolve(&self, name: &str, dir: Option<&Path>)
+        -> Option<PathBuf>
+    {
+        let here = match dir {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+        std::iter::once(here)
+            .chain(self.options.include_paths.iter().map(|p| p.join(name)))
+            .find(|path| path.is_file())
+    }
+
+    /// Take in `text` (which came from `path`, if it came from a file
+    /// at all, and was included by the file `parent`), and return its
+    /// lines. Any `.include`s in it are left for `include`.
+    pub fn load(
+        &mut self,
+        name: &str,
+        path: Option<&Path>,
+        text: &str,
+        parent: Option<usize>,
+    ) -> Vec<SourceLine> {
+        let first = self.source_lines.len();
+        self.source_lines.extend(text.lines().map(String::from));
+        let lines = first..self.source_lines.len();
+        let name = name.to_string();
+        self.files.push(SourceFile { name, lines: lines.clone() });
+
+        let canonical = path.and_then(|path| fs::canonicalize(path).ok());
+        if let Some(canonical) = &canonical {
+            self.included.insert(canonical.clone());
+        }
+        let path = path.map(Path::to_path_buf);
+        self.origins.push(Origin { path, canonical, parent });
+
+        lines.map(|line| {
+            let text = self.source_lines[line].to_string();
+            SourceLine { line, text }
+        }).collect()
+    }
+
+    /// The lines of the file named by the `.include` on `line`, or
+    /// nothing if it has been included already
+    pub fn include(&mut self, tokens: &[String], line: usize)
+        -> Result<Vec<SourceLine>, CompilationError>
+    {
+        let name = match tokens {
+            [_] => return Err(
+                CompilationError::MissingOperand(tokens[0].to_string())
+            ),
+            [_, name] => unquote(name),
+            _ => return Err(
+                CompilationError::UnexpectedToken(tokens[2].to_string())
+            ),
+        };
+        let file = self.files.iter()
+            .position(|file| file.lines.contains(&line));
+        let dir = file
+            .and_then(|file| self.origins[file].path.as_deref())
+            .and_then(Path::parent);
+        let path = self.resolve(name, dir).ok_or(
+            CompilationError::IncludeNotFound(name.to_string())
+        )?;
+        let canonical = fs::canonicalize(&path)
+            .map_err(|e| CompilationError::Io(e.to_string()))?;
+
+        // Follow the chain of files that led here, to see if this one
+        // is already being read
+        let mut including = file;
+        while let Some(file) = including {
+            let origin = &self.origins[file];
+            if origin.canonical.as_ref() == Some(&canonical) {
+                return Err(CompilationError::IncludeCycle(name.to_string()));
+            }
+            including = origin.parent;
+        }
+        if self.included.contains(&canonical) {
+            return Ok(vec![]);
+        }
+        let text = fs::read_to_string(&path)
+            .map_err(|e| CompilationError::Io(e.to_string()))?;
+        let name = path.display().to_string();
+        Ok(self.load(&name, Some(&path), &text, file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::programs::CompilationError;
+    use crate::programs::Options;
+    use crate::programs::Program;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A fresh directory full of source files
+    fn directory(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("rpu-includes-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, text) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = directory("include", &[
+            ("main.s", "put .F gp0\n.include lib/f.s\nhalt"),
+            ("lib/f.s", "; F\nnoop .F\n.include \"g.s\""),
+            ("lib/g.s", "noop"),
+        ]);
+        let path = dir.join("main.s");
+        let program = Program::try_compile_file(&path, &Options::default())
+            .unwrap();
+
+        assert_eq!(program.size(), 16);
+        assert_eq!(program.files.len(), 3);
+        assert_eq!(program.files[0].lines, 0..3);
+        assert_eq!(program.files[1].lines, 3..6);
+        assert_eq!(program.files[2].lines, 6..7);
+        assert!(program.files[2].name.ends_with("g.s"));
+
+        // noop .F (line 2 of lib/f.s)
+        assert_eq!(*program.source_addrs.get(&4).unwrap(), 4);
+        assert_eq!(program.file_of(4), Some(1));
+        // halt (line 3 of main.s)
+        assert_eq!(*program.source_addrs.get(&12).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_include_paths() {
+        let dir = directory("paths", &[
+            ("main.s", ".include f.s\n.include f.s\nhalt"),
+            ("lib/f.s", "noop"),
+        ]);
+        let path = dir.join("main.s");
+        let error = Program::try_compile_file(&path, &Options::default())
+            .err()
+            .unwrap();
+        let expected = "main.s:1: can't find f.s to include it";
+        assert!(format!("{error}").ends_with(expected));
+
+        let options = Options {
+            include_paths: vec![dir.join("lib")],
+            ..Options::default()
+        };
+        let program = Program::try_compile_file(&path, &options).unwrap();
+
+        // f.s is only included once
+        assert_eq!(program.size(), 8);
+    }
+
+    #[test]
+    fn test_conditional_include() {
+        let dir = directory("conditional", &[
+            ("main.s", ".ifdef DEBUG\n.include debug.s\n.endif\nhalt"),
+        ]);
+        let path = dir.join("main.s");
+        let program = Program::try_compile_file(&path, &Options::default())
+            .unwrap();
+        assert_eq!(program.size(), 4);
+        assert_eq!(program.files.len(), 1);
+
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), "1".to_string())],
+            ..Options::default()
+        };
+        let error = Program::try_compile_file(&path, &options)
+            .err()
+            .unwrap();
+        assert!(
+            format!("{error}").ends_with("can't find debug.s to include it")
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = directory("cycle", &[
+            ("main.s", "noop\n.include a.s"),
+            ("a.s", "noop\n\n.include b.s"),
+            ("b.s", ".include a.s"),
+        ]);
+        let path = dir.join("main.s");
+        let error = Program::try_compile_file(&path, &Options::default())
+            .err()
+            .unwrap();
+
+        let CompilationError::At { file, line, error } = error else {
+            panic!("{error:?}");
+        };
+        assert!(file.ends_with("b.s"));
+        assert_eq!(line, 1);
+        assert!(matches!(*error, CompilationError::IncludeCycle(_)));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let dir = directory("location", &[
+            ("main.s", "noop\n.include lib.s\nnoop"),
+            ("lib.s", "noop\nnoop\nbogus gp0"),
+        ]);
+        let path = dir.join("main.s");
+        let error = Program::try_compile_file(&path, &Options::default())
+            .err()
+            .unwrap();
+
+        let CompilationError::At { file, line, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert!(file.ends_with("lib.s"));
+        assert_eq!(line, 3);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::registers::RegisterName;
 use crate::registers;
+use std::fmt;
 use std::num::ParseIntError;
+use std::str::FromStr;
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, PartialEq)]
@@ -34,7 +36,7 @@ const WRITE_ID: u8 = InstructionName::write as u8;
 const READ_ID:  u8 = InstructionName::read  as u8;
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Instruction {
     halt,
     add(RegisterName, RegisterName),
@@ -50,11 +52,31 @@ pub enum Instruction {
     read(RegisterName, RegisterName),
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq)]
 pub enum ParseError {
     NoSuchInstruction(String),
     RegisterParseError(registers::ParseError),
     InvalidInt(String),
+    OperandCount { expected: usize, found: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoSuchInstruction(name) => {
+                write!(f, "there is no such instruction as {name}")
+            },
+            Self::RegisterParseError(e) => write!(f, "{e}"),
+            Self::InvalidInt(message) => {
+                write!(f, "not a valid number: {message}")
+            },
+            Self::OperandCount { expected, found } => write!(
+                f,
+                "expected {expected} operands, but found {found}"
+            ),
+        }
+    }
 }
 
 impl From<registers::ParseError> for ParseError {
@@ -69,24 +91,103 @@ impl From<ParseIntError> for ParseError {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Prints instructions the way they are written in source code, so
+/// that the text can be assembled again
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.name();
+        match self {
+            Instruction::halt | Instruction::noop => write!(f, "{name:?}"),
+            Instruction::put(val, dst) => write!(f, "put {val} {dst}"),
+            Instruction::pop(r) | Instruction::push(r) => {
+                write!(f, "{name:?} {r}")
+            },
+            Instruction::add(a, b)
+                | Instruction::copy(a, b)
+                | Instruction::jump(a, b)
+                | Instruction::mul(a, b)
+                | Instruction::sub(a, b)
+                | Instruction::write(a, b)
+                | Instruction::read(a, b) => write!(f, "{name:?} {a} {b}"),
+        }
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from_str(s)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum DecodeError {
     NoSuchInstruction(u8),
     RegisterDecodeError(registers::DecodeError)
 }
 
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoSuchInstruction(opcode) => {
+                write!(f, "there is no instruction number {opcode}")
+            },
+            Self::RegisterDecodeError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 impl From<registers::DecodeError> for DecodeError {
     fn from(other: registers::DecodeError) -> Self {
         Self::RegisterDecodeError(other)
     }
 }
 
+impl InstructionName {
+    pub fn try_parse(s: &str) -> Result<Self, ParseError> {
+        match s {
+            "halt"  => Ok(InstructionName::halt),
+            "add"   => Ok(InstructionName::add),
+            "copy"  => Ok(InstructionName::copy),
+            "jump"  => Ok(InstructionName::jump),
+            "read"  => Ok(InstructionName::read),
+            "mul"   => Ok(InstructionName::mul),
+            "noop"  => Ok(InstructionName::noop),
+            "pop"   => Ok(InstructionName::pop),
+            "push"  => Ok(InstructionName::push),
+            "put"   => Ok(InstructionName::put),
+            "write" => Ok(InstructionName::write),
+            "sub"   => Ok(InstructionName::sub),
+            _ => Err(ParseError::NoSuchInstruction(s.to_string()))
+        }
+    }
+
+    /// The number of operands this instruction takes in source code
+    pub fn arity(&self) -> usize {
+        match self {
+            InstructionName::halt => 0,
+            InstructionName::noop => 0,
+            InstructionName::pop  => 1,
+            InstructionName::push => 1,
+            _ => 2,
+        }
+    }
+}
+
 impl Instruction {
     pub fn try_from_str(s: &str) -> Result<Self, ParseError> {
         let p: Vec<&str> = s
-            .trim_end()
             .split_whitespace()
             .collect();
+        let Some(name) = p.first() else {
+            return Err(ParseError::NoSuchInstruction(String::new()));
+        };
+        let expected = InstructionName::try_parse(name)?.arity();
+        if p.len() != expected + 1 {
+            let found = p.len() - 1;
+            return Err(ParseError::OperandCount { expected, found });
+        }
         match p[0] {
             "halt" => Ok(Instruction::halt),
             "add" => {
@@ -142,50 +243,70 @@ impl Instruction {
         }
     }
 
+    pub fn name(&self) -> InstructionName {
+        match self {
+            Instruction::halt => InstructionName::halt,
+            Instruction::add(..) => InstructionName::add,
+            Instruction::copy(..) => InstructionName::copy,
+            Instruction::jump(..) => InstructionName::jump,
+            Instruction::mul(..) => InstructionName::mul,
+            Instruction::noop => InstructionName::noop,
+            Instruction::put(..) => InstructionName::put,
+            Instruction::pop(..) => InstructionName::pop,
+            Instruction::push(..) => InstructionName::push,
+            Instruction::sub(..) => InstructionName::sub,
+            Instruction::write(..) => InstructionName::write,
+            Instruction::read(..) => InstructionName::read,
+        }
+    }
+
+    /// Instructions are little-endian on every machine, so the
+    /// opcode is the first byte in memory, and the value given to
+    /// `put` is stored low byte first
     pub fn to_u32(&self) -> u32 {
         match self {
             Instruction::halt => {
-                u32::from_ne_bytes([HALT_ID,0,0,0])
+                u32::from_le_bytes([HALT_ID,0,0,0])
             },
             Instruction::add(x, y) => {
-                u32::from_ne_bytes([ADD_ID,*x as u8,*y as u8,0])
+                u32::from_le_bytes([ADD_ID,*x as u8,*y as u8,0])
             },
             Instruction::copy(src, dst) => {
-                u32::from_ne_bytes([COPY_ID,*src as u8,*dst as u8,0])
+                u32::from_le_bytes([COPY_ID,*src as u8,*dst as u8,0])
             },
             Instruction::jump(addr, cond) => {
-                u32::from_ne_bytes([JUMP_ID,*addr as u8,*cond as u8,0])
+                u32::from_le_bytes([JUMP_ID,*addr as u8,*cond as u8,0])
             },
             Instruction::mul(x, y) => {
-                u32::from_ne_bytes([MUL_ID,*x as u8,*y as u8,0])
+                u32::from_le_bytes([MUL_ID,*x as u8,*y as u8,0])
             },
             Instruction::noop => {
-                u32::from_ne_bytes([NOOP_ID,0,0,0])
+                u32::from_le_bytes([NOOP_ID,0,0,0])
             }
             Instruction::pop(dst) => {
-                u32::from_ne_bytes([POP_ID,*dst as u8,0,0])
+                u32::from_le_bytes([POP_ID,*dst as u8,0,0])
             },
             Instruction::push(src) => {
-                u32::from_ne_bytes([PUSH_ID,*src as u8,0,0])
+                u32::from_le_bytes([PUSH_ID,*src as u8,0,0])
             },
             Instruction::put(val, dst) => {
-                let v = val.to_ne_bytes();
-                u32::from_ne_bytes([PUT_ID,v[0],v[1],*dst as u8])
+                let v = val.to_le_bytes();
+                u32::from_le_bytes([PUT_ID,v[0],v[1],*dst as u8])
             },
             Instruction::sub(x, y) => {
-                u32::from_ne_bytes([SUB_ID,*x as u8,*y as u8,0])
+                u32::from_le_bytes([SUB_ID,*x as u8,*y as u8,0])
             },
             Instruction::write(src, addr) => {
-                u32::from_ne_bytes([WRITE_ID,*src as u8,*addr as u8,0])
+                u32::from_le_bytes([WRITE_ID,*src as u8,*addr as u8,0])
             },
             Instruction::read(addr, dst) => {
-                u32::from_ne_bytes([READ_ID,*addr as u8,*dst as u8,0])
+                u32::from_le_bytes([READ_ID,*addr as u8,*dst as u8,0])
             },
         }
     }
 
     pub fn try_from_u32(encoded: u32) -> Result<Self, DecodeError> {
-        let bytes = encoded.to_ne_bytes();
+        let bytes = encoded.to_le_bytes();
         let instr = bytes[0];
         match instr {
             HALT_ID => Ok(Instruction::halt),
@@ -219,7 +340,7 @@ impl Instruction {
                 Ok(Instruction::push(src))
             },
             PUT_ID => {
-                let val = u16::from_ne_bytes([bytes[1],bytes[2]]);
+                let val = u16::from_le_bytes([bytes[1],bytes[2]]);
                 let dst = RegisterName::try_decode(bytes[3])?;
                 Ok(Instruction::put(val, dst))
             },
@@ -239,7 +360,7 @@ impl Instruction {
                 Ok(Instruction::read(addr, dst))
             },
             _ => Err(
-                    DecodeError::NoSuchInstruction(instr as u8)
+                    DecodeError::NoSuchInstruction(instr)
                 )
         }
     }
@@ -325,6 +446,36 @@ mod tests {
         for (text, expected) in pairs {
             let actual = Instruction::try_from_str(text).unwrap();
             assert_eq!(actual, expected);
+            assert_eq!(actual.to_string(), text);
+            assert_eq!(text.parse::<Instruction>().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn operand_count() {
+        let pairs = vec![
+            ("halt gp0", ParseError::OperandCount { expected: 0, found: 1 }),
+            ("add gp0", ParseError::OperandCount { expected: 2, found: 1 }),
+            ("pop", ParseError::OperandCount { expected: 1, found: 0 }),
+        ];
+        for (text, expected) in pairs {
+            assert_eq!(Instruction::try_from_str(text).err(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn instruction_arity() {
+        let pairs = vec![
+            ("halt", 0),
+            ("noop", 0),
+            ("pop", 1),
+            ("push", 1),
+            ("put", 2),
+            ("jump", 2),
+        ];
+        for (text, expected) in pairs {
+            let name = InstructionName::try_parse(text).unwrap();
+            assert_eq!(name.arity(), expected);
         }
     }
 
@@ -333,7 +484,7 @@ mod tests {
         let pairs = vec![
             (
                 Instruction::halt,
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::halt as u8,
                     0,
                     0,
@@ -345,7 +496,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::add as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -357,7 +508,7 @@ mod tests {
                     RegisterName::gp3,
                     RegisterName::out
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::copy as u8,
                     RegisterName::gp3 as u8,
                     RegisterName::out as u8,
@@ -369,7 +520,7 @@ mod tests {
                     RegisterName::gp7,
                     RegisterName::gp6
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::jump as u8,
                     RegisterName::gp7 as u8,
                     RegisterName::gp6 as u8,
@@ -381,7 +532,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::mul as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -390,7 +541,7 @@ mod tests {
             ),
             (
                 Instruction::noop,
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::noop as u8,
                     0,
                     0,
@@ -401,7 +552,7 @@ mod tests {
                 Instruction::pop(
                     RegisterName::gp2
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::pop as u8,
                     RegisterName::gp2 as u8,
                     0,
@@ -412,7 +563,7 @@ mod tests {
                 Instruction::push(
                     RegisterName::gp2
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::push as u8,
                     RegisterName::gp2 as u8,
                     0,
@@ -421,10 +572,10 @@ mod tests {
             ),
             (
                 Instruction::put(7, RegisterName::gp0),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::put as u8,
-                    7_u16.to_ne_bytes()[0],
-                    7_u16.to_ne_bytes()[1],
+                    7_u16.to_le_bytes()[0],
+                    7_u16.to_le_bytes()[1],
                     RegisterName::gp0 as u8
                 ])
             ),
@@ -433,7 +584,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::sub as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -445,7 +596,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::write as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -457,7 +608,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::read as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -476,7 +627,7 @@ mod tests {
         let pairs = vec![
             (
                 Instruction::halt,
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::halt as u8,
                     0,
                     0,
@@ -488,7 +639,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::add as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -500,7 +651,7 @@ mod tests {
                     RegisterName::gp3,
                     RegisterName::out
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::copy as u8,
                     RegisterName::gp3 as u8,
                     RegisterName::out as u8,
@@ -512,7 +663,7 @@ mod tests {
                     RegisterName::gp7,
                     RegisterName::gp6
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::jump as u8,
                     RegisterName::gp7 as u8,
                     RegisterName::gp6 as u8,
@@ -524,7 +675,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::mul as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -533,7 +684,7 @@ mod tests {
             ),
             (
                 Instruction::noop,
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::noop as u8,
                     0,
                     0,
@@ -544,7 +695,7 @@ mod tests {
                 Instruction::pop(
                     RegisterName::gp2,
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::pop as u8,
                     RegisterName::gp2 as u8,
                     0,
@@ -555,7 +706,7 @@ mod tests {
                 Instruction::push(
                     RegisterName::gp2,
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::push as u8,
                     RegisterName::gp2 as u8,
                     0,
@@ -564,10 +715,10 @@ mod tests {
             ),
             (
                 Instruction::put(7, RegisterName::gp0),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::put as u8,
-                    7_u16.to_ne_bytes()[0],
-                    7_u16.to_ne_bytes()[1],
+                    7_u16.to_le_bytes()[0],
+                    7_u16.to_le_bytes()[1],
                     RegisterName::gp0 as u8
                 ])
             ),
@@ -576,7 +727,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::sub as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -588,7 +739,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::write as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -600,7 +751,7 @@ mod tests {
                     RegisterName::gp2,
                     RegisterName::gp1
                 ),
-                u32::from_ne_bytes([
+                u32::from_le_bytes([
                     InstructionName::read as u8,
                     RegisterName::gp2 as u8,
                     RegisterName::gp1 as u8,
@@ -614,4 +765,24 @@ mod tests {
             assert_eq!(expected, actual);
         }
     }
+
+    #[test]
+    fn golden_bytes() {
+        let pairs = vec![
+            (
+                Instruction::put(0x1234, RegisterName::gp1),
+                [0x09, 0x34, 0x12, 0x01],
+            ),
+            (
+                Instruction::copy(RegisterName::ans, RegisterName::out),
+                [0x02, 0x08, 0x0a, 0x00],
+            ),
+            (Instruction::halt, [0, 0, 0, 0]),
+        ];
+        for (instr, bytes) in pairs {
+            assert_eq!(instr.to_u32().to_le_bytes(), bytes);
+            let word = u32::from_le_bytes(bytes);
+            assert_eq!(Instruction::try_from_u32(word).unwrap(), instr);
+        }
+    }
 }
@@ -1,5 +1,19 @@
-mod registers;
-mod instructions;
+pub mod registers;
+mod expressions;
+mod includes;
+pub mod instructions;
+mod macros;
+mod pseudo;
 pub mod programs;
+pub mod objects;
+pub mod disassembler;
+pub mod listings;
+pub mod images;
+pub mod linker;
+pub mod builder;
+pub mod stack;
+pub mod effects;
+pub mod explain;
+pub mod datapath;
 pub mod core;
 pub mod devices;
@@ -0,0 +1,356 @@
+//! Puts relocatable programs (from `rpu asm --relocatable`) together
+//! into one program. Each program is moved to just past the end of
+//! the one before it, and every relocation is fixed up to point at
+//! wherever its label ended up.
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::mem::size_of;
+
+use crate::core::RAM;
+use crate::instructions::Instruction;
+use crate::programs::Program;
+use crate::programs::Segment;
+use crate::programs::SourceFile;
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    NothingToLink,
+
+    /// A module uses a symbol that no module exports
+    Undefined { symbol: String, module: String },
+
+    /// Two modules export the same symbol
+    Duplicate { symbol: String, first: String, second: String },
+
+    /// Only the first module can be one that wasn't assembled for
+    /// the linker, since it's the only one that stays where it is
+    NotRelocatable(String),
+
+    /// Everything together runs to this address
+    TooBig(usize),
+
+    /// A module has a relocation at an address (counted from the
+    /// start of the module) that none of its segments cover
+    BadRelocation { address: u16, module: String },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NothingToLink => write!(f, "there is nothing to link"),
+            Self::Undefined { symbol, module } => write!(
+                f,
+                "{module} uses {symbol}, but nothing exports it"
+            ),
+            Self::Duplicate { symbol, first, second } => write!(
+                f,
+                "{symbol} is exported by both {first} and {second}"
+            ),
+            Self::NotRelocatable(module) => write!(
+                f,
+                "{module} wasn't assembled for the linker, so it can \
+                only go first"
+            ),
+            Self::TooBig(end) => write!(
+                f,
+                "the linked program runs to address {end}, but there \
+                are only {RAM} bytes of memory"
+            ),
+            Self::BadRelocation { address, module } => write!(
+                f,
+                "{module} has a relocation at address {address}, which \
+                isn't part of the program"
+            ),
+        }
+    }
+}
+
+/// Where one of the linked programs ended up
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub base: u16,
+    pub size: usize,
+}
+
+/// A linked program, and what went where
+pub struct Link {
+    pub program: Program,
+    pub modules: Vec<Module>,
+
+    /// Every exported symbol, and the module that exported it
+    pub symbols: HashMap<String, (u16, usize)>,
+}
+
+/// Link programs in the order given. The first one's entry point
+/// is where the linked program starts.
+pub fn link(programs: &[(&str, &Program)]) -> Result<Link, LinkError> {
+    if programs.is_empty() {
+        return Err(LinkError::NothingToLink);
+    }
+
+    // Each program goes after the last, lined up for instructions
+    let mut modules = vec![];
+    let mut base = 0;
+    for (i, (name, program)) in programs.iter().enumerate() {
+        if i > 0 && !program.relocatable {
+            return Err(LinkError::NotRelocatable(name.to_string()));
+        }
+        let size = program.size();
+        let name = name.to_string();
+        modules.push(Module { name, base: base as u16, size });
+        base = (base + size).next_multiple_of(size_of::<Instruction>());
+    }
+    let mut symbols: HashMap<String, (u16, usize)> = HashMap::new();
+    for (i, (_, program)) in programs.iter().enumerate() {
+        for symbol in &program.exports {
+            if let Some((_, first)) = symbols.get(symbol) {
+                return Err(LinkError::Duplicate {
+                    symbol: symbol.to_string(),
+                    first: modules[*first].name.to_string(),
+                    second: modules[i].name.to_string(),
+                });
+            }
+            let address = program.labels[symbol]
+                .wrapping_add(modules[i].base);
+            symbols.insert(symbol.to_string(), (address, i));
+        }
+    }
+
+    // Checked after the symbols, since linking the same module twice
+    // is better reported as a duplicate than as too big
+    let end = modules.iter()
+        .map(|module| usize::from(module.base) + module.size)
+        .max()
+        .unwrap_or(0);
+    if end > RAM {
+        return Err(LinkError::TooBig(end));
+    }
+
+
+    let mut linked = Program::default();
+    for (i, (name, program)) in programs.iter().enumerate() {
+        let base = modules[i].base;
+        let mut segments: Vec<Segment> = program.segments.iter()
+            .map(|segment| Segment {
+                start: segment.start + base,
+                ..segment.clone()
+            })
+            .collect();
+
+        for relocation in &program.relocations {
+            let offset = match &relocation.symbol {
+                None => base,
+                Some(symbol) => match symbols.get(symbol) {
+                    Some((address, _)) => *address,
+                    None => return Err(LinkError::Undefined {
+                        symbol: symbol.to_string(),
+                        module: name.to_string(),
+                    }),
+                },
+            };
+            let address = usize::from(relocation.address + base);
+            let Some(segment) = segments.iter_mut()
+                .find(|segment| {
+                    let range = segment.range();
+                    range.contains(&address) && range.contains(&(address + 1))
+                })
+            else {
+                return Err(LinkError::BadRelocation {
+                    address: relocation.address,
+                    module: name.to_string(),
+                });
+            };
+            let i = address - usize::from(segment.start);
+            let value = u16::from_le_bytes([
+                segment.bytes[i],
+                segment.bytes[i + 1],
+            ]);
+            let value = value.wrapping_add(offset).to_le_bytes();
+            segment.bytes[i..i + 2].copy_from_slice(&value);
+        }
+        linked.segments.extend(segments);
+
+        // Keep the source code, so that the code window still works
+        let first_line = linked.source_lines.len();
+        linked.source_lines.extend(program.source_lines.iter().cloned());
+        for file in &program.files {
+            let lines = file.lines.start + first_line
+                ..file.lines.end + first_line;
+            let name = file.name.to_string();
+            linked.files.push(SourceFile { name, lines });
+        }
+        for (address, line) in &program.source_addrs {
+            linked.source_addrs.insert(address + base, line + first_line);
+        }
+    }
+
+    // Exported labels win, and otherwise the first module to use a
+    // name keeps it
+    for (symbol, (address, _)) in &symbols {
+        linked.labels.insert(symbol.to_string(), *address);
+    }
+    for (i, (_, program)) in programs.iter().enumerate() {
+        for (label, address) in &program.labels {
+            linked.labels.entry(label.to_string())
+                .or_insert(address + modules[i].base);
+        }
+    }
+    linked.entry = programs[0].1.entry;
+
+    Ok(Link { program: linked, modules, symbols })
+}
+
+impl Link {
+    /// A map file: where each module went, and every exported symbol
+    pub fn map(&self) -> String {
+        let mut map = String::new();
+        writeln!(map, "; Modules").unwrap();
+        for module in &self.modules {
+            let Module { name, base, size } = module;
+            writeln!(map, "{base:>5}  {size:>5}  {name}").unwrap();
+        }
+
+        let mut symbols: Vec<(&String, &(u16, usize))> = self.symbols.iter()
+            .collect();
+        symbols.sort_by_key(|(symbol, (address, _))| (*address, *symbol));
+        writeln!(map, "\n; Symbols").unwrap();
+        for (symbol, (address, module)) in symbols {
+            let module = &self.modules[*module].name;
+            writeln!(map, "{address:>5}  {symbol:<24} {module}").unwrap();
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Core;
+    use crate::devices::Device;
+    use crate::programs::Options;
+    use crate::programs::Relocation;
+    use std::path::Path;
+
+    fn assemble(source: &[&str]) -> Program {
+        let options = Options { relocatable: true, ..Options::default() };
+        Program::try_compile_with(&source.join("\n"), &options).unwrap()
+    }
+
+    fn main() -> Program {
+        assemble(&[
+            ".extern .DOUBLE",
+            "put 21 gp0",
+            "put .BACK gp6",
+            "jmp .DOUBLE",
+            "halt .BACK",
+        ])
+    }
+
+    fn library() -> Program {
+        assemble(&[
+            ".global .DOUBLE",
+            ".global .TWO",
+            "noop",
+            "put .TWO gp1 .DOUBLE",
+            "read gp1 gp1",
+            "mul gp0 gp1",
+            "copy ans gp0",
+            "jump gp6 zero",
+            ".TWO",
+            ".word 2",
+        ])
+    }
+
+    #[test]
+    fn test_link() {
+        let (main, library) = (main(), library());
+        let link = link(&[("main", &main), ("library", &library)]).unwrap();
+        assert_eq!(link.modules, vec![
+            Module { name: "main".to_string(), base: 0, size: 20 },
+            Module { name: "library".to_string(), base: 20, size: 26 },
+        ]);
+        assert_eq!(link.program.labels[".DOUBLE"], 24);
+        assert_eq!(link.program.labels[".TWO"], 44);
+        assert_eq!(link.program.labels[".BACK"], 16);
+
+        let mut core = Core::new();
+        core.load_program(&link.program).unwrap();
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        while core.is_running() {
+            core.execute_single_instruction(&mut devices).unwrap();
+        }
+        assert_eq!(core.register_file.gp0, 42);
+
+        let map = link.map();
+        assert!(map.contains("   20     26  library"));
+        assert!(map.contains("   24  .DOUBLE                  library"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let (main, library) = (main(), library());
+        let error = link(&[("main", &main)]).err();
+        assert_eq!(error, Some(LinkError::Undefined {
+            symbol: ".DOUBLE".to_string(),
+            module: "main".to_string(),
+        }));
+
+        let programs = [("main", &main), ("a", &library), ("b", &library)];
+        let error = link(&programs).err();
+        assert_eq!(error, Some(LinkError::Duplicate {
+            symbol: ".DOUBLE".to_string(),
+            first: "a".to_string(),
+            second: "b".to_string(),
+        }));
+
+        let absolute = Program::try_compile("halt").unwrap();
+        let error = link(&[("main", &main), ("halt", &absolute)]).err();
+        let expected = LinkError::NotRelocatable("halt".to_string());
+        assert_eq!(error, Some(expected));
+        assert!(link(&[("halt", &absolute), ("library", &library)]).is_ok());
+
+        let big = assemble(&[".org 200", ".space 40"]);
+        let error = link(&[("main", &main), ("big", &big)]).err();
+        assert_eq!(error, Some(LinkError::TooBig(260)));
+
+        // The same module twice is a duplicate, even if it's too big
+        let big = assemble(&[".global .BIG", ".BIG", ".space 200"]);
+        let error = link(&[("a", &big), ("b", &big)]).err();
+        assert_eq!(error, Some(LinkError::Duplicate {
+            symbol: ".BIG".to_string(),
+            first: "a".to_string(),
+            second: "b".to_string(),
+        }));
+
+        let mut garbled = self::library();
+        garbled.relocations.push(Relocation { address: 100, symbol: None });
+        let error = link(&[("main", &main), ("library", &garbled)]).err();
+        assert_eq!(error, Some(LinkError::BadRelocation {
+            address: 100,
+            module: "library".to_string(),
+        }));
+
+        assert_eq!(link(&[]).err(), Some(LinkError::NothingToLink));
+    }
+
+    #[test]
+    fn test_example() {
+        let options = Options { relocatable: true, ..Options::default() };
+        let compile = |path: &str| {
+            Program::try_compile_file(Path::new(path), &options).unwrap()
+        };
+        let main = compile("examples/16.linking.s");
+        let library = compile("examples/lib/print_number.s");
+        let link = link(&[("main", &main), ("library", &library)]).unwrap();
+
+        let mut core = Core::new();
+        core.load_program(&link.program).unwrap();
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        while core.is_running() {
+            core.execute_single_instruction(&mut devices).unwrap();
+        }
+        assert_eq!(&core.tty, "42");
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::programs::Placement;
+use crate::programs::Program;
+
+/// Long runs of bytes (usually from `.space`) are cut off after this
+/// many rows
+const MAX_ROWS: usize = 4;
+
+const BYTES_PER_ROW: usize = 4;
+
+impl Program {
+    /// The file name and line number of an index into `source_lines`
+    fn location(&self, line: usize) -> String {
+        match self.file_of(line) {
+            Some(file) => {
+                let file = &self.files[file];
+                format!("{}:{}", file.name, line - file.lines.start + 1)
+            },
+            None => format!("{}", line + 1),
+        }
+    }
+
+    /// The labels that each line defines or uses
+    fn labels_by_line(&self) -> HashMap<usize, Vec<&str>> {
+        let mut by_line: HashMap<usize, Vec<&str>> = HashMap::new();
+        for (label, references) in &self.references {
+            let lines = references.used.iter()
+                .chain([&references.defined]);
+            for line in lines {
+                let labels = by_line.entry(*line).or_default();
+                if !labels.contains(&label.as_str()) {
+                    labels.push(label);
+                }
+            }
+        }
+        for labels in by_line.values_mut() {
+            labels.sort();
+        }
+        by_line
+    }
+
+    /// Every source line next to the address and bytes it became,
+    /// followed by a symbol table and a cross-reference of where each
+    /// label is used
+    pub fn listing(&self) -> String {
+        let image: Vec<u8> = self.bytes().collect();
+        let mut placements = HashMap::<usize, Vec<&Placement>>::new();
+        for placement in &self.placements {
+            placements.entry(placement.line).or_default().push(placement);
+        }
+        let labels = self.labels_by_line();
+
+        let mut listing = String::new();
+        let mut file = None;
+        for (line, text) in self.source_lines.iter().enumerate() {
+            if self.file_of(line) != file {
+                file = self.file_of(line);
+                if let Some(file) = file {
+                    let name = &self.files[file].name;
+                    writeln!(listing, "; {name}").unwrap();
+                }
+            }
+
+            let mut rows = vec![];
+            for placement in placements.get(&line).into_iter().flatten() {
+                let addresses = placement.addresses.clone();
+                let bytes = &image[addresses.clone()];
+                for (i, chunk) in bytes.chunks(BYTES_PER_ROW).enumerate() {
+                    if i == MAX_ROWS {
+                        let more = bytes.len() - i * BYTES_PER_ROW;
+                        rows.push(format!("{:>5}  ({more} more bytes)", ""));
+                        break;
+                    }
+                    let address = addresses.start + i * BYTES_PER_ROW;
+                    let chunk: Vec<String> = chunk.iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect();
+                    let chunk = chunk.join(" ");
+                    rows.push(format!("{address:>5}  {chunk:<11}"));
+                }
+            }
+            let mut rows = rows.into_iter();
+            let first = rows.next()
+                .unwrap_or_else(|| format!("{:18}", ""));
+
+            let number = match file {
+                Some(file) => line - self.files[file].lines.start + 1,
+                None => line + 1,
+            };
+            let mut row = format!("{number:>5}  {first}  {text}");
+            if let Some(labels) = labels.get(&line) {
+                let values: Vec<String> = labels.iter()
+                    .map(|label| (label, self.labels[*label]))
+                    .map(|(label, address)| format!("{label} = {address}"))
+                    .collect();
+                write!(row, "  [{}]", values.join(", ")).unwrap();
+            }
+            writeln!(listing, "{}", row.trim_end()).unwrap();
+            for row in rows {
+                writeln!(listing, "{:5}  {row}", "").unwrap();
+            }
+        }
+
+        let mut names: Vec<&String> = self.labels.keys().collect();
+        names.sort();
+
+        writeln!(listing, "\n; Symbols").unwrap();
+        for name in &names {
+            let defined = match self.references.get(*name) {
+                Some(references) => self.location(references.defined),
+                None => String::new(),
+            };
+            let address = self.labels[*name];
+            writeln!(listing, "{name:<24} {address:>5}  {defined}").unwrap();
+        }
+
+        writeln!(listing, "\n; Cross-reference").unwrap();
+        for name in &names {
+            let used: Vec<String> = self.references.get(*name)
+                .map(|references| references.used.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|line| self.location(*line))
+                .collect();
+            let used = match used.is_empty() {
+                true => "(unused)".to_string(),
+                false => used.join(", "),
+            };
+            writeln!(listing, "{name:<24} {used}").unwrap();
+        }
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let source = [
+            "# Count down",
+            ".START",
+            "jmp .LOOP",
+            "halt .LOOP",
+            ".org 32",
+            ".TABLE",
+            ".word .LOOP, 2",
+            ".space 20",
+        ];
+        let program = Program::try_compile(&source.join("\n")).unwrap();
+        let listing = program.listing();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "; <source>");
+        assert_eq!(lines[1], "    1                      # Count down");
+        assert_eq!(lines[2], "    2                      .START  [.START = 0]");
+        assert_eq!(
+            lines[3],
+            "    3      0  09 08 00 07  jmp .LOOP  [.LOOP = 8]"
+        );
+        assert_eq!(lines[4], "           4  03 07 0d 00");
+        assert_eq!(
+            lines[5],
+            "    4      8  00 00 00 00  halt .LOOP  [.LOOP = 8]"
+        );
+        assert_eq!(
+            lines[8],
+            "    7     32  08 00 02 00  .word .LOOP, 2  [.LOOP = 8]"
+        );
+        assert_eq!(lines[9], "    8     36  00 00 00 00  .space 20");
+        assert_eq!(lines[13], "              (4 more bytes)");
+
+        let symbols = lines.iter().position(|line| *line == "; Symbols");
+        let symbols = &lines[symbols.unwrap() + 1..];
+        assert_eq!(
+            symbols[0],
+            ".LOOP                        8  <source>:4"
+        );
+        let xref = symbols.iter()
+            .position(|line| *line == "; Cross-reference");
+        let xref = &symbols[xref.unwrap() + 1..];
+        assert_eq!(
+            xref[0],
+            ".LOOP                    <source>:3, <source>:7"
+        );
+        assert_eq!(xref[1], ".START                   (unused)");
+    }
+
+    #[test]
+    fn test_references() {
+        let source = [
+            ".equ NEXT .B+4",
+            ".A",
+            "put NEXT gp0",
+            "jmp ..X ..X",
+            "halt .B",
+        ];
+        let program = Program::try_compile(&source.join("\n")).unwrap();
+        let references = &program.references;
+        assert_eq!(references[".B"].defined, 4);
+        assert_eq!(references[".B"].used, vec![2]);
+        assert_eq!(references[".A..X"].used, vec![3]);
+        assert!(references[".A"].used.is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+
+use crate::programs::CompilationError;
+use crate::programs::SourceLine;
+use crate::programs;
+
+/// A macro that invokes itself (directly or otherwise) will hit
+/// this limit rather than expanding forever.
+const MAX_DEPTH: usize = 32;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct Macros {
+    macros: HashMap<String, Macro>,
+
+    /// Counts expansions so that each one gets its own labels
+    expansions: usize,
+}
+
+/// Split `a, b c` into `[a, b, c]`
+fn split_list(tokens: &[String]) -> Vec<String> {
+    tokens.join(" ")
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+/// Replace every whole word in `text` that appears in `names`
+fn substitute(text: &str, names: &HashMap<String, String>) -> String {
+    let mut result = String::new();
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if is_word(c) {
+            word.push(c);
+            continue;
+        }
+        match names.get(&word) {
+            Some(replacement) => result.push_str(replacement),
+            None => result.push_str(&word),
+        }
+        word.clear();
+        result.push(c);
+    }
+    result.pop();
+    result
+}
+
+impl Macros {
+    fn check_name(&self, name: &str) -> Result<(), CompilationError> {
+        programs::check_name(name)?;
+        if programs::arity(name).is_ok() {
+            return Err(
+                CompilationError::ShadowsInstruction(name.to_string())
+            );
+        }
+        if self.macros.contains_key(name) {
+            return Err(CompilationError::Redefinition(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Record the body of a macro, up to and including `.endm`
+    fn define(
+        &mut self,
+        tokens: &[String],
+        lines: &mut impl Iterator<Item = SourceLine>,
+    ) -> Result<(), CompilationError> {
+        let Some(name) = tokens.get(1) else {
+            return Err(CompilationError::MissingOperand(
+                tokens[0].to_string()
+            ));
+        };
+        self.check_name(name)?;
+        let params = split_list(&tokens[2..]);
+        for param in &params {
+            programs::check_name(param)?;
+        }
+
+        let mut body = vec![];
+        for SourceLine { line: n, text } in lines.by_ref() {
+            let line = programs::strip_comment(&text);
+            match line.split_whitespace().next() {
+                Some(".endm") => {
+                    let name = name.to_string();
+                    self.macros.insert(name, Macro { params, body });
+                    return Ok(());
+                },
+                Some(".macro") => {
+                    return Err(CompilationError::at(n)(
+                        CompilationError::NestedMacro
+                    ));
+                },
+                _ => body.push(line.to_string()),
+            }
+        }
+        Err(CompilationError::UnterminatedMacro(name.to_string()))
+    }
+
+    /// The label (if any) defined by a line in a macro body
+    fn label_of(&self, tokens: &[String]) -> Option<String> {
+        let first = tokens.first()?;
+        if first.starts_with(".") {
+            return match tokens.len() {
+                1 => Some(first.to_string()),
+                _ => None,
+            };
+        }
+        let arity = match programs::arity(first) {
+            Ok(arity) => arity,
+            Err(_) => self.macros.get(first)?.params.len(),
+        };
+        let operands = split_list(&tokens[1..]);
+        operands.get(arity)
+            .filter(|label| label.starts_with("."))
+            .cloned()
+    }
+
+    fn invoke(
+        &mut self,
+        line: usize,
+        tokens: &[String],
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), CompilationError> {
+        let name = &tokens[0];
+        if depth >= MAX_DEPTH {
+            return Err(CompilationError::RecursiveMacro(name.to_string()));
+        }
+        let Macro { params, body } = &self.macros[name];
+        let (params, body) = (params.clone(), body.clone());
+        let mut args = split_list(&tokens[1..]);
+
+        // A label on the invocation marks the first line of the
+        // expansion, same as it would for a real instruction
+        let mut label = None;
+        if args.len() == params.len() + 1
+            && args[params.len()].starts_with(".")
+        {
+            label = args.pop();
+        }
+        if args.len() != params.len() {
+            return Err(CompilationError::ArgumentCount {
+                name: name.to_string(),
+                expected: params.len(),
+                found: args.len(),
+            });
+        }
+
+        self.expansions += 1;
+        let mut names: HashMap<String, String> = params.iter()
+            .cloned()
+            .zip(args)
+            .collect();
+        for line in &body {
+            let tokens = programs::tokenize(line);
+            if let Some(label) = self.label_of(&tokens) {
+                let unique = format!("{label}@{}", self.expansions);
+                names.insert(label, unique);
+            }
+        }
+        let body: Vec<String> = body.iter()
+            .map(|line| substitute(line, &names))
+            .collect();
+
+        if let Some(label) = label {
+            output.push(SourceLine { line, text: label });
+        }
+        for text in body {
+            let tokens = programs::tokenize(&text);
+            match tokens.first() {
+                Some(first) if self.macros.contains_key(first) => {
+                    self.invoke(line, &tokens, depth + 1, output)?;
+                },
+                _ => output.push(SourceLine { line, text }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle `tokens` if they start a macro definition or invoke a
+    /// macro, taking the rest of a definition from `lines` and adding
+    /// an expansion to `output`. Returns whether they did. Expanded
+    /// code keeps the line number of the line that invoked the macro.
+    pub fn line(
+        &mut self,
+        n: usize,
+        tokens: &[String],
+        lines: &mut impl Iterator<Item = SourceLine>,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<bool, CompilationError> {
+        match tokens.first().map(String::as_str) {
+            Some(".macro") => {
+                self.define(tokens, lines)
+                    .map_err(CompilationError::at(n))?;
+            },
+            Some(".endm") => {
+                return Err(CompilationError::at(n)(
+                    CompilationError::UnexpectedEndm
+                ));
+            },
+            Some(name) if self.macros.contains_key(name) => {
+                self.invoke(n, tokens, 0, output)
+                    .map_err(CompilationError::at(n))?;
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Pass over a macro definition that `.if` leaves out, without
+/// defining anything. Returns whether `tokens` started one.
+pub(crate) fn skip(
+    n: usize,
+    tokens: &[String],
+    lines: &mut impl Iterator<Item = SourceLine>,
+) -> Result<bool, CompilationError> {
+    if tokens.first().is_none_or(|first| first != ".macro") {
+        return Ok(false);
+    }
+    for SourceLine { text, .. } in lines.by_ref() {
+        let tokens = programs::tokenize(&text);
+        if tokens.first().is_some_and(|first| first == ".endm") {
+            return Ok(true);
+        }
+    }
+    let name = tokens.get(1).map_or("", String::as_str);
+    Err(CompilationError::at(n)(
+        CompilationError::UnterminatedMacro(name.to_string())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &[&str]) -> Vec<SourceLine> {
+        source.iter()
+            .enumerate()
+            .map(|(line, text)| SourceLine { line, text: text.to_string() })
+            .collect()
+    }
+
+    /// Expand every macro invocation in `source`, dropping the macro
+    /// definitions themselves
+    fn expand(source: &[SourceLine])
+        -> Result<Vec<SourceLine>, CompilationError>
+    {
+        let mut macros = Macros::default();
+        let mut output = vec![];
+        let mut lines = source.iter().map(|line| SourceLine {
+            line: line.line,
+            text: line.text.to_string(),
+        });
+        while let Some(SourceLine { line, text }) = lines.next() {
+            let tokens = programs::tokenize(&text);
+            if !macros.line(line, &tokens, &mut lines, &mut output)? {
+                output.push(SourceLine { line, text });
+            }
+        }
+        Ok(output)
+    }
+
+    fn texts(source: &[&str]) -> Vec<String> {
+        expand(&lines(source)).unwrap()
+            .into_iter()
+            .map(|expanded| expanded.text.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_substitution() {
+        let expanded = texts(&[
+            ".macro SUM x, y",
+            "    add x y",
+            ".endm",
+            "SUM gp0, gp1",
+            "SUM gp2 gp3",
+        ]);
+        assert_eq!(expanded, vec!["add gp0 gp1", "add gp2 gp3"]);
+    }
+
+    #[test]
+    fn test_unique_labels() {
+        let expanded = texts(&[
+            ".macro SKIP",
+            "    put .over gp0",
+            "    jump gp0 zero",
+            "    noop .over",
+            ".endm",
+            "SKIP",
+            "SKIP",
+        ]);
+        assert_eq!(expanded[0], "put .over@1 gp0");
+        assert_eq!(expanded[2], "noop .over@1");
+        assert_eq!(expanded[3], "put .over@2 gp0");
+        assert_eq!(expanded[5], "noop .over@2");
+    }
+
+    #[test]
+    fn test_nested_invocation() {
+        let source = [
+            ".macro ONE r",
+            "    put 1 r",
+            ".endm",
+            ".macro TWO a, b",
+            "    ONE a",
+            "    ONE b",
+            ".endm",
+            "noop",
+            "TWO gp0, gp1 .HERE",
+        ];
+        let expanded = expand(&lines(&source)).unwrap();
+
+        let lines: Vec<usize> = expanded.iter()
+            .map(|expanded| expanded.line)
+            .collect();
+        assert_eq!(lines, vec![7, 8, 8, 8]);
+        assert_eq!(expanded[1].text, ".HERE");
+        assert_eq!(expanded[3].text.trim(), "put 1 gp1");
+    }
+
+    #[test]
+    fn test_recursion() {
+        let source = [
+            ".macro FOREVER",
+            "    FOREVER",
+            ".endm",
+            "FOREVER",
+        ];
+        let error = expand(&lines(&source)).err().unwrap();
+
+        let CompilationError::At { line, error, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert_eq!(line, 4);
+        assert!(matches!(*error, CompilationError::RecursiveMacro(_)));
+    }
+
+    #[test]
+    fn test_argument_count() {
+        let source = [
+            ".macro SUM x, y",
+            "    add x y",
+            ".endm",
+            "SUM gp0",
+        ];
+        let error = expand(&lines(&source)).err().unwrap();
+
+        let CompilationError::At { error, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert!(matches!(
+            *error,
+            CompilationError::ArgumentCount { expected: 2, found: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let source = [
+            "noop",
+            ".macro OOPS",
+            "    noop",
+        ];
+        let error = expand(&lines(&source)).err().unwrap();
+
+        let CompilationError::At { line, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert_eq!(line, 2);
+    }
+}
@@ -13,10 +13,34 @@ use ratatui::text::   Line;
 use ratatui::widgets::List;
 use ratatui::widgets::ListState;
 use ratatui::widgets::Paragraph; 
+use ratatui::widgets::Wrap;
 use clap::            Parser;
+use clap::            Subcommand;
+use std::path::       Path;
 use std::path::       PathBuf;
+use std::fs;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use rpu::objects;
+use rpu::disassembler;
+use rpu::images;
+use rpu::linker;
+use rpu::programs::   Options;
 use rpu::programs::   Program; 
 use rpu::core::       RAM;
+use rpu::core::       ExecutionError;
+use rpu::core::       CoreState;
+use rpu::effects::    Effect;
+use rpu::explain;
+use rpu::datapath::Unit;
+use rpu::datapath::Wire;
+use rpu::registers::  RegisterName;
+use rpu::registers::  RegisterFile;
+use rpu::instructions::Instruction;
+use rpu::programs::   SegmentKind;
+use std::ops::        Range;
+use ratatui::widgets::Cell;
 use ratatui::layout:: Rect; 
 use color_eyre::      Result; 
 use ratatui::widgets::Row; 
@@ -27,27 +51,275 @@ use ratatui::widgets::Table;
 use ratatui::widgets::TableState; 
 use rpu::             devices;
 use crossterm::       event; 
-use std::             fs; 
+use color_eyre::eyre::eyre;
+
+mod editor;
+use editor::Action;
+use editor::Editor;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(value_name = "FILE")]
-    source: PathBuf
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A source file, an object file made by `rpu asm`, or a memory
+    /// image (FILE.hex or FILE.bin)
+    #[arg(value_name = "FILE", required = true)]
+    source: Option<PathBuf>,
+
+    /// Reload FILE whenever it changes
+    #[arg(long)]
+    watch: bool,
+
+    #[command(flatten)]
+    assembler: AssemblerArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble FILE without running it
+    Asm {
+        #[arg(value_name = "FILE")]
+        source: PathBuf,
+
+        /// Where to write the result [default: FILE.rpo, FILE.hex or
+        /// FILE.bin, depending on the format]
+        #[arg(short = 'o', value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+
+        /// What kind of file to write
+        #[arg(long, value_enum, default_value_t = Format::Object)]
+        format: Format,
+
+        /// Also print a listing: each line with its address and bytes,
+        /// followed by every label and where it is used
+        #[arg(long)]
+        listing: bool,
+
+        /// Make an object file for `rpu link`, which may use labels
+        /// from other object files (see .extern)
+        #[arg(short = 'r', long)]
+        relocatable: bool,
+
+        #[command(flatten)]
+        assembler: AssemblerArgs,
+    },
+
+    /// Put object files made with `rpu asm -r` together into one
+    /// program, starting with the first
+    Link {
+        #[arg(value_name = "OBJECT", required = true)]
+        objects: Vec<PathBuf>,
+
+        /// Where to write the linked program
+        #[arg(short = 'o', value_name = "OUTPUT")]
+        output: PathBuf,
+
+        /// What kind of file to write
+        #[arg(long, value_enum, default_value_t = Format::Object)]
+        format: Format,
+
+        /// Also write a map of where everything went to MAP
+        #[arg(long, value_name = "MAP")]
+        map: Option<PathBuf>,
+    },
+
+    /// Turn an object file, or a raw memory image, back into source
+    Disasm {
+        #[arg(value_name = "FILE")]
+        source: PathBuf,
+
+        /// The address that a memory image starts at
+        #[arg(long, value_name = "ADDRESS", default_value_t = 0)]
+        base: u16,
+    },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Format {
+    /// An rpu object file, which remembers labels and source lines
+    Object,
+
+    /// Intel HEX
+    Hex,
+
+    /// Raw bytes, starting from address 0
+    Bin,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Object => "rpo",
+            Self::Hex => "hex",
+            Self::Bin => "bin",
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct AssemblerArgs {
+    /// Also look for included files in DIR
+    #[arg(short = 'I', value_name = "DIR")]
+    include: Vec<PathBuf>,
+
+    /// Define a constant, as if by .equ (VALUE defaults to 1)
+    #[arg(short = 'D', value_name = "NAME[=VALUE]")]
+    define: Vec<String>,
+}
+
+impl AssemblerArgs {
+    fn options(&self) -> Options {
+        let defines = self.define.iter()
+            .map(|define| match define.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (define.to_string(), "1".to_string()),
+            })
+            .collect();
+        let include_paths = self.include.clone();
+        Options { include_paths, defines, ..Options::default() }
+    }
+}
+
+/// Is this a file that `open` would assemble?
+fn is_source(path: &Path) -> Result<bool> {
+    let bytes = fs::read(path)?;
+    let extension = path.extension().and_then(|e| e.to_str());
+    let image = matches!(extension, Some("hex") | Some("bin"));
+    Ok(!image && !objects::is_object(&bytes))
+}
+
+/// Assemble a source file, or read an object file or memory image
+/// (which is told apart by its extension: `.hex` or `.bin`)
+fn open(path: &Path, options: &Options) -> Result<Program> {
+    let bytes = fs::read(path)?;
+    let name = path.display().to_string();
+    let extension = path.extension().and_then(|e| e.to_str());
+    let program = match extension {
+        _ if objects::is_object(&bytes) => {
+            Program::read_object(path).map_err(|e| eyre!("{e}"))?
+        },
+        Some("hex") => {
+            let text = String::from_utf8_lossy(&bytes);
+            let segments = images::read_hex(&text)
+                .map_err(|e| eyre!("{e}"))?;
+            Program::from_image(&name, segments)
+        },
+        Some("bin") => {
+            let segment = images::read_bin(&bytes, 0)
+                .map_err(|e| eyre!("{e}"))?;
+            Program::from_image(&name, vec![segment])
+        },
+        _ => Program::try_compile_file(path, options)
+            .map_err(|e| eyre!("{e}"))?,
+    };
+    Ok(program)
+}
+
+fn assemble(
+    source: &Path,
+    output: Option<PathBuf>,
+    format: Format,
+    listing: bool,
+    options: &Options,
+) -> Result<()> {
+    let program = Program::try_compile_file(source, options)
+        .map_err(|e| eyre!("{e}"))?;
+    if listing {
+        print!("{}", program.listing());
+    }
+    let output = output
+        .unwrap_or_else(|| source.with_extension(format.extension()));
+    save(&program, &output, format)
+}
+
+fn save(program: &Program, output: &Path, format: Format) -> Result<()> {
+    let bytes = match format {
+        Format::Object => program.to_object(),
+        Format::Hex => program.to_hex().into_bytes(),
+        Format::Bin => program.to_bin(),
+    };
+    fs::write(output, bytes)?;
+    Ok(())
+}
+
+fn link(
+    objects: &[PathBuf],
+    output: &Path,
+    format: Format,
+    map: Option<PathBuf>,
+) -> Result<()> {
+    let mut programs = vec![];
+    for path in objects {
+        let program = Program::read_object(path).map_err(|e| {
+            eyre!("{}: {e}", path.display())
+        })?;
+        programs.push((path.display().to_string(), program));
+    }
+    let programs: Vec<(&str, &Program)> = programs.iter()
+        .map(|(name, program)| (name.as_str(), program))
+        .collect();
+    let link = linker::link(&programs).map_err(|e| eyre!("{e}"))?;
+    if let Some(map) = map {
+        fs::write(map, link.map())?;
+    }
+    save(&link.program, output, format)
+}
+
+/// Print the code in an object file, or in any other file full of
+/// bytes (like a dump of memory)
+fn disassemble(source: &Path, base: u16) -> Result<()> {
+    let bytes = fs::read(source)?;
+    let lines = match objects::is_object(&bytes) {
+        true => Program::from_object(&bytes)
+            .map_err(|e| eyre!("{e}"))?
+            .disassemble(),
+        false => disassembler::disassemble(&bytes, base),
+    };
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(())
 }
 
 pub fn main() -> Result<()> {
     let args = Args::parse();
 
-    let source = fs::read_to_string(&args.source)?;
+    color_eyre::install()?;
+    match args.command {
+        Some(Command::Asm {
+            source,
+            output,
+            format,
+            listing,
+            relocatable,
+            assembler,
+        }) => {
+            let options = Options { relocatable, ..assembler.options() };
+            return assemble(&source, output, format, listing, &options);
+        },
+        Some(Command::Link { objects, output, format, map }) => {
+            return link(&objects, &output, format, map);
+        },
+        Some(Command::Disasm { source, base }) => {
+            return disassemble(&source, base);
+        },
+        None => {},
+    }
+    let source = args.source.expect("clap requires FILE");
+    let options = args.assembler.options();
     let mut core = Core::new();
-    let program = Program::try_compile(&source).unwrap();
-    core.load_program(&program).unwrap();
+    let program = open(&source, &options)?;
+    core.load_program(&program).map_err(|e| eyre!("{e}"))?;
 
-
-    color_eyre::install()?;
     let terminal = ratatui::init();
-    let computer = Computer::new(core, program);
+    let mut computer = Computer::new(core, program, source, options);
+    if args.watch {
+        computer.watch = modified(&computer.source);
+    }
     let result = run(terminal, computer);
     ratatui::restore();
     result
@@ -59,65 +331,345 @@ fn run(
 ) -> Result<()> {
     loop {
         terminal.draw(|f| { render(&mut computer,f); })?;
-        match event::read()? {
-            Event::Key(ke) => {  
-                match ke.code {
-                    KeyCode::Esc => {
-                        break Ok(())
-                    },
-                    KeyCode::Char('q') => {
-                        break Ok(())
-                    },
-                    KeyCode::Down => {
-                        let new = computer.code_list_state
-                            .offset() + 1;
-                        let current = computer.code_list_state
-                            .offset_mut();
-                        *current = new;
-                    },
-                    KeyCode::Up => {
-                        let old = computer.code_list_state
-                            .offset();
-                        let new = if old == 0 {
-                            0
-                        } else {
-                            old - 1
-                        };
-                        let current = computer.code_list_state
-                            .offset_mut();
-                        *current = new;
-                    },
-                    KeyCode::PageDown => {
-                        computer.memory_table_state
-                            .select_next();
-                    },
-                    KeyCode::PageUp => {
-                        computer.memory_table_state
-                            .select_previous();
-                    },
-                    KeyCode::Char('n') => {
-                        let mut devices: Vec<&mut dyn Device> = vec![
-                            &mut computer.lcd0,
-                            &mut computer.lcd1,
-                        ];
-                        let r = computer.core
-                            .execute_single_instruction(
-                                &mut devices
-                            );
-                        match r {
-                            Ok(false) => { continue; },
-                            Ok(true) => { break Ok(()) },
-                            Err(e) => {
-                                computer.core.tty +=
-                                    &format!("{:?}\n", e);
-                                continue;
-                            }
-                        }
+
+        // While running, wait for a key only until the next tick,
+        // and while watching, only until it's time to look again
+        let timeout = match computer.clock.running {
+            true => Some(computer.clock.timeout()),
+            false => None,
+        };
+        let timeout = match computer.watch {
+            Some(_) => Some(timeout.unwrap_or(WATCH).min(WATCH)),
+            None => timeout,
+        };
+        let event = match timeout {
+            Some(timeout) => match event::poll(timeout)? {
+                true => Some(event::read()?),
+                false => None,
+            },
+            None => Some(event::read()?),
+        };
+        if computer.source_changed() {
+            computer.reload();
+        }
+        if computer.clock.running {
+            for _ in 0..computer.clock.due() {
+                match computer.step() {
+                    Ok(false) if computer.core.is_running() => {},
+                    Ok(false) => {
+                        computer.clock.running = false;
+                        break;
                     },
-                    _ => {},
+                    Ok(true) => { return Ok(()) },
+                    Err(_) => { break; },
                 }
+            }
+        }
+
+        if let Some(Event::Key(ke)) = event {
+            if computer.editing {
+                computer.edit_key(ke);
+                continue;
+            }
+            if computer.selection.is_some() {
+                computer.select_key(ke);
+                continue;
+            }
+            match ke.code {
+                KeyCode::Esc => {
+                    break Ok(())
+                },
+                KeyCode::Char('q') => {
+                    break Ok(())
+                },
+                KeyCode::Down => {
+                    let new = computer.code_list_state
+                        .offset() + 1;
+                    let current = computer.code_list_state
+                        .offset_mut();
+                    *current = new;
+                },
+                KeyCode::Up => {
+                    let old = computer.code_list_state
+                        .offset();
+                    let new = if old == 0 {
+                        0
+                    } else {
+                        old - 1
+                    };
+                    let current = computer.code_list_state
+                        .offset_mut();
+                    *current = new;
+                },
+                KeyCode::Tab => {
+                    let count = computer.program.files.len();
+                    computer.code_file = (computer.code_file + 1) % count;
+                    *computer.code_list_state.offset_mut() = 0;
+                },
+                KeyCode::PageDown => {
+                    computer.memory_table_state
+                        .select_next();
+                },
+                KeyCode::PageUp => {
+                    computer.memory_table_state
+                        .select_previous();
+                },
+                KeyCode::Char(' ') => {
+                    let running = !computer.clock.running;
+                    computer.clock.run(running && computer.core.is_running());
+                },
+                KeyCode::Char('+') | KeyCode::Char('=') => {
+                    computer.clock.faster();
+                },
+                KeyCode::Char('-') => {
+                    computer.clock.slower();
+                },
+                KeyCode::Char('r') => {
+                    computer.reset();
+                },
+                KeyCode::Char('l') => {
+                    computer.reload();
+                },
+                KeyCode::Char('e') => {
+                    computer.edit();
+                },
+                KeyCode::Char('s') => {
+                    computer.selection = Some(Selection {
+                        target: Target::Register(RegisterName::gp0),
+                        input: None,
+                    });
+                },
+                KeyCode::Char('u') => {
+                    computer.unpoke();
+                },
+                KeyCode::Char('m') => {
+                    computer.memory_view = computer.memory_view.next();
+                },
+                KeyCode::Char('n') => {
+                    computer.clock.run(false);
+                    match computer.step() {
+                        Ok(false) => { continue; },
+                        Ok(true) => { break Ok(()) },
+                        Err(_) => { continue; },
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// The registers that show up in the register tables, in order
+const REGISTERS: [RegisterName; 12] = [
+    RegisterName::gp0,
+    RegisterName::gp1,
+    RegisterName::gp2,
+    RegisterName::gp3,
+    RegisterName::gp4,
+    RegisterName::gp5,
+    RegisterName::gp6,
+    RegisterName::gp7,
+    RegisterName::ans,
+    RegisterName::dvc,
+    RegisterName::pc,
+    RegisterName::sp,
+];
+
+/// Something in the register or memory tables that can be changed
+/// by hand
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    Register(RegisterName),
+    Byte(usize),
+    Word(usize),
+}
+
+struct Selection {
+    target: Target,
+
+    /// The new value, while it's being typed in
+    input: Option<String>,
+}
+
+/// A change made by hand, and what was there before it
+enum Poke {
+    Register(RegisterName, u16),
+    Memory(usize, Vec<u8>),
+}
+
+/// Read a value typed in by hand, in decimal or (after 0x) in hex
+fn parse_value(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+impl Target {
+    /// Move around the register and memory tables
+    fn moved(self, rows: isize, columns: isize) -> Self {
+        match self {
+            Self::Register(name) => {
+                let i = REGISTERS.iter().position(|r| *r == name)
+                    .unwrap_or(0);
+                // The special purpose registers are the second row
+                let i = match (rows, i < 8) {
+                    (1, true) => 8 + i.min(3),
+                    (-1, false) => i - 8,
+                    _ => i,
+                };
+                let i = (i as isize + columns)
+                    .clamp(0, REGISTERS.len() as isize - 1);
+                Self::Register(REGISTERS[i as usize])
             },
-            _ => {}
+            Self::Byte(address) | Self::Word(address) => {
+                let size = match self {
+                    Self::Word(_) => 2,
+                    _ => 1,
+                };
+                let last = (RAM - size) as isize;
+                let address = (address as isize + rows * 8 + columns)
+                    .clamp(0, last) as usize;
+                match self {
+                    Self::Word(_) => Self::Word(address),
+                    _ => Self::Byte(address),
+                }
+            },
+        }
+    }
+
+    /// The addresses this covers in memory
+    fn addresses(self) -> Option<Range<usize>> {
+        match self {
+            Self::Register(_) => None,
+            Self::Byte(address) => Some(address..address + 1),
+            Self::Word(address) => Some(address..address + 2),
+        }
+    }
+}
+
+/// How often to look at the source file with `--watch`
+const WATCH: Duration = Duration::from_millis(500);
+
+/// When a file was last changed
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Clock rates to choose from with `+` and `-`, in Hz
+const CLOCK_RATES: [u32; 13] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000,
+];
+
+/// Don't redraw the screen more often than this while running
+const FRAME: Duration = Duration::from_millis(1000 / 60);
+
+/// Runs the core on its own, a tick at a time
+struct Clock {
+    running: bool,
+
+    /// An index into CLOCK_RATES
+    rate: usize,
+
+    /// When the last tick that has been executed was due
+    last_tick: Instant,
+
+    /// Instructions executed so far, whether running or stepping
+    executed: u64,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Self {
+            running: false,
+            rate: 3,
+            last_tick: Instant::now(),
+            executed: 0,
+        }
+    }
+
+    fn hz(&self) -> u32 {
+        CLOCK_RATES[self.rate]
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_secs(1) / self.hz()
+    }
+
+    fn run(&mut self, running: bool) {
+        self.running = running;
+        self.last_tick = Instant::now();
+    }
+
+    fn faster(&mut self) {
+        self.rate = (self.rate + 1).min(CLOCK_RATES.len() - 1);
+    }
+
+    fn slower(&mut self) {
+        self.rate = self.rate.saturating_sub(1);
+    }
+
+    /// How long to wait for a key before it's time to run again.
+    /// Fast clocks run a frame's worth of ticks at a time.
+    fn timeout(&self) -> Duration {
+        self.period().max(FRAME)
+            .saturating_sub(self.last_tick.elapsed())
+    }
+
+    /// How many ticks have come due since the last call. If the
+    /// terminal fell behind, the ticks it missed are dropped rather
+    /// than run in one go.
+    fn due(&mut self) -> u32 {
+        let elapsed = self.last_tick.elapsed();
+        let ticks = (elapsed.as_secs_f64() * f64::from(self.hz())) as u32;
+        match ticks > self.hz() {
+            true => self.last_tick = Instant::now(),
+            false => self.last_tick += self.period() * ticks,
+        }
+        ticks.min(self.hz())
+    }
+}
+
+/// Ways of looking at memory, which `m` cycles through
+#[derive(Clone, Copy, PartialEq)]
+enum MemoryView {
+    Decimal,
+    Hex,
+
+    /// 16-bit values, low byte first
+    Words,
+
+    /// Each 16-bit value as the console would print it
+    Text,
+    Instructions,
+}
+
+impl MemoryView {
+    fn next(self) -> Self {
+        match self {
+            Self::Decimal => Self::Hex,
+            Self::Hex => Self::Words,
+            Self::Words => Self::Text,
+            Self::Text => Self::Instructions,
+            Self::Instructions => Self::Decimal,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Decimal => "decimal",
+            Self::Hex => "hex",
+            Self::Words => "words",
+            Self::Text => "text",
+            Self::Instructions => "instructions",
+        }
+    }
+
+    fn bytes_per_cell(self) -> usize {
+        match self {
+            Self::Decimal | Self::Hex => 1,
+            Self::Words | Self::Text => 2,
+            Self::Instructions => 4,
         }
     }
 }
@@ -129,36 +681,388 @@ struct Computer {
     lcd1: LCD,
     code_list_state: ListState,
     memory_table_state: TableState,
+    memory_view: MemoryView,
+    clock: Clock,
+
+    /// Which of the program's source files is in the code window
+    code_file: usize,
+
+    /// Where the program came from, for reloading it
+    source: PathBuf,
+    options: Options,
+
+    /// When the source was last changed, if it's being watched
+    watch: Option<SystemTime>,
+
+    /// Why the last reload failed
+    errors: Option<String>,
+
+    /// The source being edited. It's kept after editing stops, so
+    /// that unsaved changes aren't lost.
+    editor: Option<Editor>,
+    editing: bool,
+
+    /// What's being changed by hand in the register or memory tables
+    selection: Option<Selection>,
+
+    /// Every change made by hand since the last reset, so that they
+    /// can be undone
+    pokes: Vec<Poke>,
+
+    /// The lowest sp has been since the last reset
+    deepest: u16,
+
+    /// What the last instruction did
+    effect: Option<Effect>,
 }
 
 impl Computer {
-    fn new(core: Core, program: Program) -> Self {
-        Self {
+    fn new(
+        core: Core,
+        program: Program,
+        source: PathBuf,
+        options: Options,
+    ) -> Self {
+        // Start the memory window on the row where execution begins
+        let entry_row = usize::from(program.entry) / 8;
+        let mut computer = Self {
             core,
             program,
             lcd0: LCD::default(),
             lcd1: LCD::default(),
             code_list_state: ListState::default(),
             memory_table_state: TableState::new()
-                .with_selected(Some(0)),
+                .with_selected(Some(entry_row)),
+            memory_view: MemoryView::Decimal,
+            clock: Clock::new(),
+            code_file: 0,
+            source,
+            options,
+            watch: None,
+            errors: None,
+            editor: None,
+            editing: false,
+            selection: None,
+            pokes: vec![],
+            deepest: 0,
+            effect: None,
+        };
+        computer.deepest = computer.core.register_file.sp;
+        computer.follow_pc();
+        computer
+    }
+
+    /// Start the program over, as if it had just been loaded
+    fn reset(&mut self) {
+        let mut core = Core::new();
+        core.load_program(&self.program)
+            .expect("the program was loaded once already");
+        self.core = core;
+        self.lcd0 = LCD::default();
+        self.lcd1 = LCD::default();
+        self.clock.run(false);
+        self.clock.executed = 0;
+        self.pokes.clear();
+        self.deepest = self.core.register_file.sp;
+        self.effect = None;
+
+        let entry_row = usize::from(self.program.entry) / 8;
+        self.memory_table_state.select(Some(entry_row));
+        self.follow_pc();
+    }
+
+    /// Read the source again and start over with it. If it doesn't
+    /// assemble, or doesn't fit, the old program stays.
+    fn reload(&mut self) {
+        match open(&self.source, &self.options) {
+            Ok(program) => self.replace(program),
+            Err(e) => self.errors = Some(e.to_string()),
+        }
+        if self.editor.as_ref().is_some_and(|editor| !editor.modified) {
+            self.editor = None;
+        }
+    }
+
+    /// Start over with a new program, unless it doesn't fit
+    fn replace(&mut self, program: Program) {
+        if let Err(e) = Core::new().load_program(&program) {
+            self.errors = Some(e.to_string());
+            return;
+        }
+        self.program = program;
+        self.errors = None;
+        self.code_file = 0;
+        *self.code_list_state.offset_mut() = 0;
+        self.reset();
+    }
+
+    /// Switch the code window over to editing the source, starting
+    /// at the line being executed
+    fn edit(&mut self) {
+        if self.editor.is_none() {
+            match is_source(&self.source) {
+                Ok(true) => {},
+                Ok(false) => {
+                    self.errors = Some(
+                        "only source files can be edited".to_string()
+                    );
+                    return;
+                },
+                Err(e) => {
+                    self.errors = Some(e.to_string());
+                    return;
+                },
+            }
+            let mut editor = match Editor::open(&self.source) {
+                Ok(editor) => editor,
+                Err(e) => {
+                    self.errors = Some(e.to_string());
+                    return;
+                },
+            };
+            let pc = self.core.register_file.pc;
+            let file = &self.program.files[0];
+            let line = self.program.source_addrs.get(&pc)
+                .filter(|line| file.lines.contains(line));
+            if let Some(line) = line {
+                editor.go_to(line - file.lines.start);
+            }
+            self.editor = Some(editor);
+        }
+        self.clock.run(false);
+        self.editing = true;
+    }
+
+    fn edit_key(&mut self, key: event::KeyEvent) {
+        let Some(editor) = &mut self.editor else {
+            return;
+        };
+        match editor.key(key) {
+            Action::Nothing => {},
+            Action::Save => self.save(),
+            Action::Close => self.editing = false,
+        }
+    }
+
+    fn select_key(&mut self, key: event::KeyEvent) {
+        let Some(selection) = &mut self.selection else {
+            return;
+        };
+        if let Some(input) = &mut selection.input {
+            match key.code {
+                KeyCode::Char(c) if c.is_ascii_hexdigit() || c == 'x' => {
+                    input.push(c);
+                },
+                KeyCode::Backspace => { input.pop(); },
+                KeyCode::Esc => selection.input = None,
+                KeyCode::Enter => {
+                    let (target, input) = (selection.target, input.clone());
+                    selection.input = None;
+                    self.poke(target, &input);
+                },
+                _ => {},
+            }
+            return;
+        }
+
+        let target = selection.target;
+        selection.target = match key.code {
+            KeyCode::Left => target.moved(0, -1),
+            KeyCode::Right => target.moved(0, 1),
+            KeyCode::Up => target.moved(-1, 0),
+            KeyCode::Down => target.moved(1, 0),
+            KeyCode::Tab => match target {
+                Target::Register(_) => {
+                    let row = self.memory_table_state.selected()
+                        .unwrap_or(0);
+                    Target::Byte((row * 8).min(RAM - 1))
+                },
+                _ => Target::Register(RegisterName::gp0),
+            },
+            KeyCode::Char('w') => match target {
+                Target::Byte(address) => Target::Word(address.min(RAM - 2)),
+                Target::Word(address) => Target::Byte(address),
+                _ => target,
+            },
+            _ => target,
+        };
+        match key.code {
+            KeyCode::Enter => selection.input = Some(String::new()),
+            KeyCode::Char(c) if c.is_ascii_digit() => {
+                selection.input = Some(c.to_string());
+            },
+            KeyCode::Char('u') => self.unpoke(),
+            KeyCode::Esc => self.selection = None,
+            _ => {},
+        }
+        if let Some(Target::Byte(address) | Target::Word(address)) =
+            self.selection.as_ref().map(|selection| selection.target)
+        {
+            self.memory_table_state.select(Some(address / 8));
+        }
+    }
+
+    /// Change a register or memory by hand. Registers follow the
+    /// same rules as the `put` instruction.
+    fn poke(&mut self, target: Target, input: &str) {
+        let Some(value) = parse_value(input) else {
+            self.errors = Some(format!("{input} isn't a 16-bit number"));
+            return;
+        };
+        let poke = match target {
+            Target::Register(name) => {
+                let old = self.core.register_file.read(name)
+                    .map_err(ExecutionError::from)
+                    .and_then(|old| {
+                        self.core.put(value, name)?;
+                        Ok(old)
+                    });
+                match old {
+                    Ok(old) => Poke::Register(name, old),
+                    Err(e) => {
+                        self.errors = Some(e.to_string());
+                        return;
+                    },
+                }
+            },
+            Target::Byte(address) => {
+                let Ok(byte) = u8::try_from(value) else {
+                    self.errors = Some(
+                        format!("{value} doesn't fit in a byte")
+                    );
+                    return;
+                };
+                let old = self.core.memory[address];
+                self.core.memory[address] = byte;
+                Poke::Memory(address, vec![old])
+            },
+            Target::Word(address) => {
+                let addresses = address..address + 2;
+                let old = self.core.memory[addresses.clone()].to_vec();
+                self.core.memory[addresses]
+                    .copy_from_slice(&value.to_le_bytes());
+                Poke::Memory(address, old)
+            },
+        };
+        self.pokes.push(poke);
+    }
+
+    /// Undo the last change made by hand
+    fn unpoke(&mut self) {
+        match self.pokes.pop() {
+            Some(Poke::Register(name, old)) => {
+                self.core.register_file.write(name, old)
+                    .expect("it was a register that could be read");
+            },
+            Some(Poke::Memory(address, old)) => {
+                let addresses = address..address + old.len();
+                self.core.memory[addresses].copy_from_slice(&old);
+            },
+            None => {},
+        }
+    }
+
+    /// Save the source being edited, and assemble it. If that works,
+    /// the machine starts over with the new program.
+    fn save(&mut self) {
+        let Some(editor) = &mut self.editor else {
+            return;
+        };
+        if let Err(e) = editor.save() {
+            self.errors = Some(e.to_string());
+            return;
+        }
+        // Don't let --watch load it all over again
+        self.watch = self.watch.and(modified(&self.source));
+
+        match Program::try_compile_file(&self.source, &self.options) {
+            Ok(program) => {
+                editor.diagnose(None);
+                self.replace(program);
+            },
+            Err(e) => {
+                editor.diagnose(Some(&e));
+                self.errors = Some(e.to_string());
+            },
+        }
+    }
+
+    /// Has the watched source changed since it was last loaded?
+    fn source_changed(&mut self) -> bool {
+        let Some(watched) = self.watch else {
+            return false;
+        };
+        let modified = modified(&self.source);
+        self.watch = modified.or(self.watch);
+        modified.is_some_and(|modified| modified != watched)
+    }
+
+    /// Execute one instruction. A fault is written to the console
+    /// and stops the clock.
+    fn step(&mut self) -> Result<bool, ExecutionError> {
+        if !self.core.is_running() {
+            return Ok(false);
+        }
+        let mut devices: Vec<&mut dyn Device> = vec![
+            &mut self.lcd0,
+            &mut self.lcd1,
+        ];
+        let before = self.core.snapshot();
+        let result = self.core.execute_single_instruction(&mut devices);
+        self.effect = Some(Effect::since(&before, &self.core));
+        self.deepest = self.deepest.min(self.core.register_file.sp);
+        self.follow_pc();
+        match &result {
+            Ok(_) => self.clock.executed += 1,
+            Err(e) => {
+                let pc = self.core.register_file.pc;
+                self.core.tty += &format!("Fault at {pc}: {e}\n");
+                self.clock.running = false;
+            },
+        }
+        result
+    }
+
+    /// Show whichever file holds the next instruction
+    fn follow_pc(&mut self) {
+        let pc = self.core.register_file.pc;
+        let file = self.program.source_addrs.get(&pc)
+            .and_then(|line| self.program.file_of(*line));
+        if let Some(file) = file {
+            self.code_file = file;
         }
     }
 }
 
 struct Layouts {
+    status: Rect,
     code: Rect,
+    errors: Rect,
+    datapath: Rect,
+    explanation: Rect,
     help: Rect,
     lcd0: Rect,
     lcd1: Rect,
     memory: Rect,
     printer: Rect,
     power_led: Rect,
+    clock: Rect,
+    stack: Rect,
     registers: Rect,
     special_registers: Rect,
 }
 
 impl Layouts {
-    fn new(frame: &Frame) -> Self {
+    fn new(frame: &Frame, errors: Option<&str>) -> Self {
+        let screen = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ])
+            .split(frame.area());
+        let status = screen[1];
+
         let layout = Layout::default()
             .direction(Direction::Horizontal)
             .constraints(vec![
@@ -166,17 +1070,27 @@ impl Layouts {
                 Constraint::Min(31),
                 Constraint::Min(55),
             ])
-            .split(frame.area());
+            .split(screen[0]);
 
+        // The errors panel only shows up when there are errors
+        let errors = errors
+            .map(|errors| errors.lines().count().min(8) as u16 + 2)
+            .unwrap_or(0);
         let lefthand_layout = Layout::default()
             .direction(Direction::Vertical)
             .constraints(vec![
                 Constraint::Fill(1),
-                Constraint::Length(4)
+                Constraint::Length(errors),
+                Constraint::Length(DATAPATH_HEIGHT as u16 + 2),
+                Constraint::Length(5),
+                Constraint::Length(9)
             ])
             .split(layout[0]);
         let code = lefthand_layout[0];
-        let help = lefthand_layout[1];
+        let errors = lefthand_layout[1];
+        let datapath = lefthand_layout[2];
+        let explanation = lefthand_layout[3];
+        let help = lefthand_layout[4];
 
         let devices_layout = Layout::default()
             .direction(Direction::Vertical)
@@ -184,13 +1098,17 @@ impl Layouts {
                 Constraint::Length(7),
                 Constraint::Length(7),
                 Constraint::Length(3),
+                Constraint::Length(4),
+                Constraint::Fill(1),
                 Constraint::Fill(1),
             ])
             .split(layout[1]);
         let lcd0 = devices_layout[0];
         let lcd1 = devices_layout[1];
         let power_led = devices_layout[2];
-        let printer = devices_layout[3];
+        let clock = devices_layout[3];
+        let stack = devices_layout[4];
+        let printer = devices_layout[5];
 
         let tools_layout = Layout::default()
             .direction(Direction::Vertical)
@@ -205,7 +1123,11 @@ impl Layouts {
         let memory = tools_layout[2];
 
         Self {
+            status,
             code,
+            errors,
+            datapath,
+            explanation,
             help,
             lcd0,
             lcd1,
@@ -214,23 +1136,46 @@ impl Layouts {
             special_registers,
             printer,
             power_led,
+            clock,
+            stack,
         }
     }
 }
 
 fn render(computer: &mut Computer, frame: &mut Frame) {
-    let layouts = Layouts::new(frame);
+    let layouts = Layouts::new(frame, computer.errors.as_deref());
 
 
-    render_code(
-        &computer.program,
-        computer.core.register_file.pc,
-        &mut computer.code_list_state,
-        layouts.code,
-        frame,
-        "Code"
+    let code_title = format!(
+        "Code: {}",
+        computer.program.files[computer.code_file].name
     );
-    render_help(layouts.help, frame, "Help");
+    match &mut computer.editor {
+        Some(editor) if computer.editing => {
+            let title = format!("Editing: {}", editor.path.display());
+            editor.render(layouts.code, frame, &title);
+        },
+        _ => render_code(
+            &computer.program,
+            &computer.core,
+            computer.code_file,
+            &mut computer.code_list_state,
+            layouts.code,
+            frame,
+            &code_title
+        ),
+    }
+    if let Some(errors) = &computer.errors {
+        render_printer(errors, layouts.errors, frame, "Errors");
+    }
+    render_datapath(&computer.core, layouts.datapath, frame);
+    render_explanation(&computer.core, layouts.explanation, frame);
+    let help = match computer.selection {
+        Some(_) => "Help: arrows and Tab choose, w for a byte or word, \
+            type a number and Enter to change it, Esc when done",
+        None => "Help",
+    };
+    render_help(layouts.help, frame, help);
     computer.lcd0.render(
         layouts.lcd0,
         frame,
@@ -242,30 +1187,44 @@ fn render(computer: &mut Computer, frame: &mut Frame) {
         "LCD1 (dvc 1)"
     );
     render_led(
-        computer.core.power,
+        &computer.core.state,
         layouts.power_led,
         frame,
         "Power"
     );
+    render_clock(&computer.clock, layouts.clock, frame, "Clock");
+    render_stack(
+        &computer.core,
+        &computer.program,
+        computer.deepest,
+        layouts.stack,
+        frame,
+    );
     render_printer(
         &computer.core.tty,
         layouts.printer,
         frame,
         "Error Console"
     );
+    render_status(computer.effect.as_ref(), layouts.status, frame);
 
+    let selection = computer.selection.as_ref()
+        .map(|selection| (selection.target, selection.input.as_deref()));
+    let register_file = &computer.core.register_file;
     let gp_registers = vec![
-        ("gp0", computer.core.register_file.gp0),
-        ("gp1", computer.core.register_file.gp1),
-        ("gp2", computer.core.register_file.gp2),
-        ("gp3", computer.core.register_file.gp3),
-        ("gp4", computer.core.register_file.gp4),
-        ("gp5", computer.core.register_file.gp5),
-        ("gp6", computer.core.register_file.gp6),
-        ("gp7", computer.core.register_file.gp7),
+        (RegisterName::gp0, register_file.gp0),
+        (RegisterName::gp1, register_file.gp1),
+        (RegisterName::gp2, register_file.gp2),
+        (RegisterName::gp3, register_file.gp3),
+        (RegisterName::gp4, register_file.gp4),
+        (RegisterName::gp5, register_file.gp5),
+        (RegisterName::gp6, register_file.gp6),
+        (RegisterName::gp7, register_file.gp7),
     ];
     render_registers(
         gp_registers,
+        selection,
+        computer.effect.as_ref(),
         layouts.registers, 
         frame,
         "General Purpose Registers"
@@ -273,13 +1232,15 @@ fn render(computer: &mut Computer, frame: &mut Frame) {
 
 
     let sp_registers = vec![
-        ("ans", computer.core.register_file.ans),
-        ("dvc", computer.core.register_file.dvc),
-        ("pc", computer.core.register_file.pc),
-        ("sp", computer.core.register_file.sp),
+        (RegisterName::ans, register_file.ans),
+        (RegisterName::dvc, register_file.dvc),
+        (RegisterName::pc, register_file.pc),
+        (RegisterName::sp, register_file.sp),
     ];
     render_registers(
         sp_registers,
+        selection,
+        computer.effect.as_ref(),
         layouts.special_registers,
         frame,
         "Special Purpose Registers"
@@ -287,33 +1248,112 @@ fn render(computer: &mut Computer, frame: &mut Frame) {
 
 
     // Memory
-    render_memory(
-        &computer.core.memory,
-        &mut computer.memory_table_state,
-        layouts.memory,
-        frame,
-        "Memory"
-    );
+    render_memory(computer, layouts.memory, frame);
 }
 
 fn render_code(
     program: &Program,
-    pc: u16,
+    core: &Core,
+    file: usize,
     state: &mut ListState,
     area: Rect, 
     frame: &mut Frame,
     title: &str,
 ) {
-    let current_line = program.source_addrs.get(&pc);
-    state.select(current_line.copied());
-    let items = program.source_lines.clone();
+    let lines = program.files[file].lines.clone();
+    let pc = core.register_file.pc;
+    let current_line = program.source_addrs.get(&pc)
+        .filter(|line| lines.contains(line))
+        .map(|line| line - lines.start);
+    state.select(current_line);
+    let items = program.source_lines[lines].to_vec();
+    // After a fault, pc is still on the instruction that failed
+    let highlight = match core.state {
+        CoreState::Faulted(_) => Style::new().bold().white().on_red(),
+        _ => Style::new().italic().red(),
+    };
     let list = List::new(items)
         .block(common_block(title))
-        .highlight_style(Style::new().italic().red());
+        .highlight_style(highlight);
 
     frame.render_stateful_widget(list, area, state);
 }
 
+fn render_clock(
+    clock: &Clock,
+    area: Rect,
+    frame: &mut Frame,
+    title: &str,
+) {
+    let state = match clock.running {
+        true => Span::styled(" RUNNING ", Style::new().black().on_green()),
+        false => Span::styled(" PAUSED ", Style::new().black().on_yellow()),
+    };
+    let text = vec![
+        Line::from(vec![
+            state,
+            Span::raw(format!(" {} Hz", clock.hz())),
+        ]),
+        Line::from(format!("{} instructions", clock.executed)),
+    ];
+    let paragraph = Paragraph::new(text)
+        .block(common_block(title));
+    frame.render_widget(paragraph, area);
+}
+
+fn render_stack(
+    core: &Core,
+    program: &Program,
+    deepest: u16,
+    area: Rect,
+    frame: &mut Frame,
+) {
+    // sp can be put anywhere, including below the bottom of the stack
+    let depth = |sp: u16| match (RAM - 2).checked_sub(usize::from(sp)) {
+        Some(used) => (used / 2).to_string(),
+        None => "invalid".to_string(),
+    };
+    let sp = core.register_file.sp;
+    let mut lines = vec![
+        Line::from(format!(
+            "depth {}, deepest {}",
+            depth(sp),
+            depth(deepest)
+        )).bold(),
+    ];
+
+    // The calls in progress come first, since the values they're
+    // worked out from are still listed below
+    let calls = core.call_stack(program);
+    for call in &calls {
+        let name = call.name.clone()
+            .unwrap_or_else(|| call.routine.to_string());
+        let text = format!("in {name}, back to {}", call.return_address);
+        lines.push(Line::styled(text, Style::new().magenta()));
+    }
+
+    for entry in core.stack(program) {
+        let is_call = calls.iter()
+            .any(|call| call.address == entry.address);
+        let style = match is_call {
+            true => Style::new().magenta(),
+            false => Style::new(),
+        };
+        let text = format!(
+            "{:>5}: {:>5} {}",
+            entry.address,
+            entry.value,
+            entry.labels.join(" ")
+        );
+        lines.push(Line::styled(text, style));
+    }
+
+    let title = format!("Stack (sp {sp})");
+    let paragraph = Paragraph::new(lines)
+        .block(common_block(&title));
+    frame.render_widget(paragraph, area);
+}
+
 fn render_help(
     area: Rect,
     frame: &mut Frame,
@@ -343,9 +1383,68 @@ fn render_help(
             Span::raw(" - scroll mem window")
         ])
     ];
+    let text_tab = vec![
+        Line::from(vec![
+            Span::styled("Tab", Style::new().bold()),
+            Span::raw(" - switch code file")
+        ])
+    ];
+    let text_space = vec![
+        Line::from(vec![
+            Span::styled("Space", Style::new().bold()),
+            Span::raw(" - run/pause")
+        ])
+    ];
+    let text_plus = vec![
+        Line::from(vec![
+            Span::styled("+/-", Style::new().bold()),
+            Span::raw(" - clock faster/slower")
+        ])
+    ];
+    let text_r = vec![
+        Line::from(vec![
+            Span::styled("r", Style::new().bold()),
+            Span::raw(" - reset")
+        ])
+    ];
+    let text_l = vec![
+        Line::from(vec![
+            Span::styled("l", Style::new().bold()),
+            Span::raw(" - reload the source file")
+        ])
+    ];
+    let text_e = vec![
+        Line::from(vec![
+            Span::styled("e", Style::new().bold()),
+            Span::raw(" - edit the source file")
+        ])
+    ];
+    let text_s = vec![
+        Line::from(vec![
+            Span::styled("s", Style::new().bold()),
+            Span::raw(" - change registers/memory")
+        ])
+    ];
+    let text_u = vec![
+        Line::from(vec![
+            Span::styled("u", Style::new().bold()),
+            Span::raw(" - undo a change")
+        ])
+    ];
+    let text_m = vec![
+        Line::from(vec![
+            Span::styled("m", Style::new().bold()),
+            Span::raw(" - change the memory view")
+        ])
+    ];
     let rows = [
         Row::new([text_n, text_q]),
-        Row::new([text_up, text_pgup])
+        Row::new([text_up, text_pgup]),
+        Row::new([text_tab, text_space]),
+        Row::new([text_plus, text_r]),
+        Row::new([text_l, text_e]),
+        Row::new([text_s, text_u]),
+        Row::new([text_m, vec![]]),
     ];
     let widths = vec![
         Constraint::Length(28), Constraint::Length(31)
@@ -357,18 +1456,20 @@ fn render_help(
 }
 
 fn render_led(
-    power: bool,
+    state: &CoreState,
     area: Rect,
     frame: &mut Frame,
     title: &str,
 ) {
-    let content = match power {
-        true =>  " ON  ",
-        false => " OFF "
+    let content = match state {
+        CoreState::Running =>  " ON  ",
+        CoreState::Halted => " OFF ",
+        CoreState::Faulted(_) => " FAULT ",
     };
-    let style = match power {
-        true => Style::new().black().on_green(),
-        false => Style::new().white().on_red(),
+    let style = match state {
+        CoreState::Running => Style::new().black().on_green(),
+        CoreState::Halted => Style::new().white().on_red(),
+        CoreState::Faulted(_) => Style::new().bold().black().on_yellow(),
     };
     let text = vec![
         Line::from(vec![
@@ -384,6 +1485,7 @@ fn render_led(
     frame.render_widget(paragraph, area);
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Default)]
 struct LCD {
     value: u16
@@ -407,6 +1509,7 @@ impl devices::Device for LCD {
 }
 
 impl LCD {
+    #[allow(clippy::needless_range_loop)]
     fn render(
         &self,
         area: Rect,
@@ -444,7 +1547,7 @@ impl LCD {
 
                 content.push_str(lcd_font[char_id][row]);
             }
-            content.push_str("\n");
+            content.push('\n');
         }
 
         let paragraph = Paragraph::new(content)
@@ -461,26 +1564,209 @@ fn render_printer(
     title: &str
 ) {
     let paragraph = Paragraph::new(text.to_string())
+        .wrap(Wrap { trim: false })
         .block(common_block(title));
     frame.render_widget(paragraph, area);
 }
 
+/// How many rows the picture of the datapath takes up
+const DATAPATH_HEIGHT: usize = 13;
+
+/// A wire, pointing right or left, with whatever is on it
+fn wire(label: Option<&str>, width: usize, rightwards: bool) -> String {
+    let label = label.map(|label| format!(" {label} ")).unwrap_or_default();
+    let label: String = label.chars().take(width - 3).collect();
+    let line = "─".repeat(width - 2 - label.chars().count());
+    match rightwards {
+        true => format!("─{label}{line}▶"),
+        false => format!("◀─{label}{line}"),
+    }
+}
+
+/// A unit's box, with its name in the middle
+fn unit_box(name: &[&str], width: usize, height: usize) -> Vec<String> {
+    let inside = width - 2;
+    let top = (height - 2 - name.len()) / 2;
+    let mut rows = vec![format!("┌{}┐", "─".repeat(inside))];
+    for row in 0..height - 2 {
+        let text = row.checked_sub(top)
+            .and_then(|line| name.get(line))
+            .unwrap_or(&"");
+        rows.push(format!("│{text:^inside$}│"));
+    }
+    rows.push(format!("└{}┘", "─".repeat(inside)));
+    rows
+}
+
+/// The machine's parts and the wires between them, with the ones the
+/// instruction at pc uses lit up and labelled with their values
+fn render_datapath(core: &Core, area: Rect, frame: &mut Frame) {
+    let pc = core.register_file.pc;
+    let datapath = match core.state {
+        CoreState::Running => core.instruction_at(pc.into())
+            .map(|instruction| core.datapath(instruction)),
+        _ => None,
+    }.unwrap_or_default();
+    let style = |active: bool| match active {
+        true => Style::new().bold().yellow(),
+        false => Style::new().dark_gray(),
+    };
+    let blank = |width: usize| (" ".repeat(width), Style::new());
+
+    // Each column is drawn from top to bottom, and then the columns
+    // are put side by side
+    let unit = |unit: Unit, name: &[&str], width, height| {
+        unit_box(name, width, height).into_iter()
+            .map(|row| (row, style(datapath.uses(unit))))
+            .collect::<Vec<_>>()
+    };
+    let wires = |width, wires: &[(usize, Wire, bool)]| {
+        (0..DATAPATH_HEIGHT)
+            .map(|row| match wires.iter().find(|(r, _, _)| *r == row) {
+                Some((_, w, rightwards)) => {
+                    let label = datapath.label(*w);
+                    (wire(label, width, *rightwards), style(label.is_some()))
+                },
+                None => blank(width),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut left = unit(Unit::Incrementer, &["+4"], 10, 4);
+    left.extend(unit(Unit::Jump, &["jump", "align -4"], 10, 4));
+    left.resize(DATAPATH_HEIGHT, blank(10));
+    let left_wires = wires(18, &[
+        (1, Wire::PcOut, false),
+        (2, Wire::PcIn, true),
+        (5, Wire::JumpIn, false),
+        (6, Wire::JumpOut, true),
+    ]);
+    let mut registers = unit(Unit::Registers, &[], 13, DATAPATH_HEIGHT);
+    registers[3] = (format!("│{:^11}│", "registers"), registers[3].1);
+    if let Some(label) = datapath.label(Wire::Transfer) {
+        registers[9] = (format!("│{label:^11}│"), style(true));
+    }
+    let right_wires = wires(18, &[
+        (1, Wire::AluA, true),
+        (2, Wire::AluB, true),
+        (3, Wire::AluOut, false),
+        (6, Wire::Address, true),
+        (7, Wire::MemoryIn, true),
+        (8, Wire::MemoryOut, false),
+        (11, Wire::DeviceBus, true),
+    ]);
+    let mut right = unit(Unit::Alu, &["ALU"], 10, 5);
+    right.extend(unit(Unit::Memory, &["memory"], 10, 5));
+    right.extend(unit(Unit::Devices, &["devices"], 10, 3));
+
+    let columns = [left, left_wires, registers, right_wires, right];
+    let lines: Vec<Line> = (0..DATAPATH_HEIGHT)
+        .map(|row| {
+            Line::from(columns.iter()
+                .map(|column| {
+                    let (text, style) = &column[row];
+                    Span::styled(text.clone(), *style)
+                })
+                .collect::<Vec<_>>())
+        })
+        .collect();
+    let paragraph = Paragraph::new(lines)
+        .block(common_block("Datapath"));
+    frame.render_widget(paragraph, area);
+}
+
+/// What the instruction at pc is about to do, in words
+fn render_explanation(core: &Core, area: Rect, frame: &mut Frame) {
+    let pc = core.register_file.pc;
+    let text = match &core.state {
+        CoreState::Running => match core.instruction_at(pc.into()) {
+            Some(instruction) => {
+                explain::explain(instruction, &core.register_file)
+            },
+            None => format!(
+                "The bytes at {pc} aren't an instruction, so running \
+                them will fail."
+            ),
+        },
+        CoreState::Halted => {
+            "The machine has halted. Press r to start over.".to_string()
+        },
+        CoreState::Faulted(e) => {
+            format!("The instruction at {pc} failed: {e}. Press r to \
+                start over.")
+        },
+    };
+    let paragraph = Paragraph::new(text)
+        .wrap(Wrap { trim: true })
+        .block(common_block("What Happens Next"));
+    frame.render_widget(paragraph, area);
+}
+
+/// One line at the bottom saying what the last instruction did
+fn render_status(effect: Option<&Effect>, area: Rect, frame: &mut Frame) {
+    let line = match effect {
+        Some(effect) => {
+            let instruction = effect.instruction.as_ref()
+                .map(|instruction| instruction.to_string())
+                .unwrap_or_else(|| "not an instruction".to_string());
+            Line::from(vec![
+                Span::styled(
+                    format!(" {}: {instruction} ", effect.address),
+                    Style::new().bold(),
+                ),
+                Span::raw(format!("-> {effect}")),
+            ])
+        },
+        None => Line::from(" Nothing has run yet").dim(),
+    };
+    frame.render_widget(Paragraph::new(line), area);
+}
+
+/// How a value that can be changed by hand looks: highlighted while
+/// it's selected, and showing what's being typed in
+fn selected_cell<'a>(
+    value: String,
+    selected: bool,
+    input: Option<&str>,
+) -> Cell<'a> {
+    match (selected, input) {
+        (true, Some(input)) => Cell::from(format!("{input:>4}_"))
+            .style(Style::new().black().on_yellow()),
+        (true, None) => Cell::from(value).style(Style::new().reversed()),
+        (false, _) => Cell::from(value),
+    }
+}
+
 fn render_registers(
-    pairs: Vec<(&str, u16)>,
+    pairs: Vec<(RegisterName, u16)>,
+    selection: Option<(Target, Option<&str>)>,
+    effect: Option<&Effect>,
     area: Rect,
     frame: &mut Frame,
     title: &str
 ) {
-    let cells: Vec<String> = (&pairs).into_iter().map(|(_, val)| {
-        format!("{:5}", val)
+    let cells: Vec<Cell> = pairs.iter().map(|(name, val)| {
+        let (selected, input) = match selection {
+            Some((Target::Register(register), input)) => {
+                (register == *name, input)
+            },
+            _ => (false, None),
+        };
+        // Registers the last instruction changed stand out
+        let changed = effect.is_some_and(|effect| effect.changed(*name));
+        match (selected, changed) {
+            (false, true) => Cell::from(format!("{:5}", val))
+                .style(Style::new().bold().yellow()),
+            _ => selected_cell(format!("{:5}", val), selected, input),
+        }
     }).collect();
     let rows = [Row::new(cells)];
-    let widths: Vec<Constraint> = (&pairs).into_iter().map(|_| {
+    let widths: Vec<Constraint> = pairs.iter().map(|_| {
         Constraint::Length(5)
     }).collect();
     let block = common_block(title);
-    let header_cells: Vec<String> = (&pairs).into_iter().map(|(name, _)| {
-        format!("{:>5}", name)
+    let header_cells: Vec<String> = pairs.iter().map(|(name, _)| {
+        format!("{:>5}", name.to_string())
     }).collect();
     let header = Row::new(header_cells)
         .style(Style::new().bold());
@@ -491,68 +1777,229 @@ fn render_registers(
     frame.render_widget(table, area);
 }
 
-fn render_memory(
+/// What a byte of memory is being used for, which decides its colour
+#[derive(PartialEq)]
+enum Region {
+    Code,
+    Data,
+
+    /// Values pushed onto the stack, from just above `sp` to the top
+    /// of memory
+    Stack,
+
+    /// Not part of the program, or the stack
+    Unused,
+}
+
+impl Region {
+    fn of(address: usize, program: &Program, sp: u16) -> Self {
+        if address >= usize::from(sp) + 2 {
+            return Self::Stack;
+        }
+        let segment = program.segments.iter()
+            .find(|segment| segment.range().contains(&address));
+        match segment.map(|segment| &segment.kind) {
+            Some(SegmentKind::Code) => Self::Code,
+            Some(_) => Self::Data,
+            None => Self::Unused,
+        }
+    }
+
+    fn style(&self) -> Style {
+        match self {
+            Self::Code => Style::new().cyan(),
+            Self::Data => Style::new().yellow(),
+            Self::Stack => Style::new().magenta(),
+            Self::Unused => Style::new().dark_gray(),
+        }
+    }
+}
+
+/// One cell of the memory window, in whichever view it's in
+fn memory_cell(
+    view: MemoryView,
     memory: &[u8; RAM],
-    state: &mut TableState,
-    area: Rect,
-    frame: &mut Frame,
-    title: &str,
-) {
+    program: &Program,
+    address: usize,
+) -> String {
+    let word = |address: usize| match address + 1 < RAM {
+        true => u16::from_le_bytes([memory[address], memory[address + 1]]),
+        false => u16::from(memory[address]),
+    };
+    match view {
+        MemoryView::Decimal => format!("{:5}", memory[address]),
+        MemoryView::Hex => format!("   {:02x}", memory[address]),
+        MemoryView::Words => format!("{:>11}", word(address)),
+        MemoryView::Text => {
+            // The way the console would print it
+            let text = match word(address) {
+                10 => "'\\n'".to_string(),
+                value => match char::from_u32(value.into()) {
+                    Some(c) if !c.is_control() => format!("'{c}'"),
+                    _ => "·".to_string(),
+                },
+            };
+            format!("{text:>11}")
+        },
+        MemoryView::Instructions => {
+            let bytes: [u8; 4] = memory[address..address + 4]
+                .try_into()
+                .expect("rows hold whole instructions");
+            let instruction = u32::from_le_bytes(bytes);
+            let text = match Instruction::try_from_u32(instruction) {
+                Ok(instruction) => instruction.to_string(),
+                Err(_) => {
+                    format!(".word {}, {}", word(address), word(address + 2))
+                },
+            };
+            let mut labels: Vec<&String> = program.labels.iter()
+                .filter(|(_, value)| usize::from(**value) == address)
+                .map(|(label, _)| label)
+                .collect();
+            labels.sort();
+            match labels.first() {
+                Some(label) => format!("{text} {label}"),
+                None => text,
+            }
+        },
+    }
+}
+
+fn render_memory(computer: &mut Computer, area: Rect, frame: &mut Frame) {
+    let Computer {
+        core,
+        program,
+        memory_view,
+        memory_table_state: state,
+        selection,
+        effect,
+        ..
+    } = computer;
+    let view = *memory_view;
+    let effect = effect.as_ref();
+    let (selected, input) = match selection {
+        Some(selection) => {
+            (selection.target.addresses(), selection.input.as_deref())
+        },
+        None => (None, None),
+    };
+    let selected = selected.unwrap_or_default();
+    let RegisterFile { pc, sp, .. } = core.register_file;
+    let (pc, sp) = (usize::from(pc), usize::from(sp));
+    let size = view.bytes_per_cell();
+
     let mut rows: Vec<Row> = vec![];
-    let mut current_row: Vec<String> = vec![];
-    current_row.push(String::from("    0"));
-    for (addr, byte) in memory.iter().enumerate() {
-        current_row.push(format!("{:5}", byte));
-        if (addr + 1) % 8 == 0 {
-            let style = match (addr / 8) % 2 == 0 {
-                true => Style::default(),
-                false => Style::default(),
+    for row in (0..RAM).step_by(8) {
+        // The rows holding pc and sp are marked by their addresses
+        let address_style = match row / 8 {
+            n if n == pc / 8 => Style::new().black().on_green(),
+            n if n == sp / 8 => Style::new().black().on_magenta(),
+            _ => Style::new(),
+        };
+        let mut cells = vec![
+            Cell::from(format!("{row:>5}")).style(address_style),
+        ];
+        for address in (row..row + 8).step_by(size) {
+            let addresses = address..address + size;
+            let text = memory_cell(view, &core.memory, program, address);
+            let chosen = addresses.clone()
+                .any(|address| selected.contains(&address));
+            // What's typed in shows up where the selection starts
+            let input = input.filter(|_| {
+                addresses.contains(&selected.start)
+            });
+            let changed = effect.is_some_and(|effect| {
+                addresses.clone().any(|address| effect.changed_byte(address))
+            });
+            let region = Region::of(address, program, sp as u16);
+            let cell = match (chosen, changed) {
+                (true, _) => selected_cell(text, true, input),
+                (false, true) => Cell::from(text)
+                    .style(region.style().bold().reversed()),
+                (false, false) => Cell::from(text).style(region.style()),
             };
-            rows.push(
-                Row::new(current_row).style(style)
-            );
-            current_row = vec![];
-            current_row.push(format!("{:>5}", addr+1));
-        }
-    }
-    let widths = [
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-        Constraint::Length(5),
-    ];
+            cells.push(cell);
+        }
+        rows.push(Row::new(cells));
+    }
+
+    // Whatever the view, a row takes up as much room as 8 bytes
+    let count = 8 / size;
+    let width = (8 * 6 - 1 - (count - 1)) / count;
+    let mut widths = vec![Constraint::Length(5)];
+    widths.extend((0..count).map(|_| Constraint::Length(width as u16)));
+    let mut header = vec!["ADDR ".to_string()];
+    header.extend((0..8).step_by(size).map(|offset| {
+        let offset = format!("+{offset}");
+        match view {
+            MemoryView::Instructions => format!("{offset:<width$}"),
+            _ => format!("{offset:>width$}"),
+        }
+    }));
+
+    let title = format!("Memory ({})", view.name());
+    let legend = Line::from(vec![
+        Span::styled("[code", Region::Code.style()),
+        Span::styled(" data", Region::Data.style()),
+        Span::styled(" stack", Region::Stack.style()),
+        Span::raw(" "),
+        Span::styled("pc", Style::new().black().on_green()),
+        Span::raw(" "),
+        Span::styled("sp", Style::new().black().on_magenta()),
+        Span::raw("]"),
+    ]);
     let table = Table::new(rows, widths)
         .column_spacing(1)
         .header(
-            Row::new(vec![
-                "ADDR ",
-                "   +0",
-                "   +1",
-                "   +2",
-                "   +3",
-                "   +4",
-                "   +5",
-                "   +6",
-                "   +7",
-            ])
+            Row::new(header)
                 .style(Style::new().bold())
                 .bottom_margin(1)
         )
         .row_highlight_style(Style::new().red().italic())
-        .block( common_block(title));
+        .block(common_block(&title).title_bottom(legend));
     frame.render_stateful_widget(table, area, state);
 
 }
 
-fn common_block(title: &str) -> Block {
+fn common_block(title: &str) -> Block<'_> {
     let title = format!("[{title}]");
     Block::new()
         .title(title)
         .borders(Borders::ALL)
         .border_style(Style::new().blue())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn computer(source: &str) -> Computer {
+        let program = Program::try_compile(source).unwrap();
+        let mut core = Core::new();
+        core.load_program(&program).unwrap();
+        let source = PathBuf::from("<source>");
+        Computer::new(core, program, source, Options::default())
+    }
+
+    #[test]
+    fn test_poke() {
+        let mut computer = computer("push gp0\nhalt");
+
+        // Mistakes go to the errors panel, not the machine's console
+        computer.poke(Target::Register(RegisterName::pc), "8");
+        computer.poke(Target::Byte(0), "300");
+        assert_eq!(
+            computer.errors.as_deref(),
+            Some("300 doesn't fit in a byte")
+        );
+        assert_eq!(computer.core.tty, "");
+        assert_eq!(computer.core.register_file.pc, 0);
+
+        // An sp poked past the stack faults the push, and that's all
+        computer.poke(Target::Register(RegisterName::sp), "255");
+        assert_eq!(computer.core.register_file.sp, 255);
+        let error = computer.step();
+        assert_eq!(error, Err(ExecutionError::OutOfMemory(255)));
+        assert!(!computer.core.is_running());
+    }
+}
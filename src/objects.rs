@@ -0,0 +1,507 @@
+//! Assembled programs, saved to disk so that they can be run without
+//! their source code.
+//!
+//! An object file is a header followed by four tables. Every number
+//! is little-endian, and every string and byte run is preceded by its
+//! length as a `u16`.
+//!
+//! ```text
+//! magic    "RPUO"
+//! version  u16
+//! entry    u16
+//! segments u16 count, then (kind u8, start u16, bytes) each
+//! files    u16 count, then (name, line count u32) each
+//! symbols  u16 count, then (name, address u16) each
+//! lines    u16 count, then (address u16, line u32) each
+//! flags    u8: 1 if the program is relocatable
+//! exports  u16 count, then a name each
+//! imports  u16 count, then a name each
+//! relocations
+//!          u16 count, then (address u16, symbol u16) each, where
+//!          symbol is 0 for the program's own labels, or 1 plus an
+//!          index into the imports
+//! ```
+//!
+//! Version 1 files stop after the lines, and are read as programs
+//! that can't be linked.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::programs::Program;
+use crate::programs::Relocation;
+use crate::programs::Segment;
+use crate::programs::SegmentKind;
+use crate::programs::SourceFile;
+
+pub const MAGIC: &[u8; 4] = b"RPUO";
+
+/// Bump this whenever the layout changes
+pub const VERSION: u16 = 2;
+
+/// More lines of source than anyone could mean, so a garbled line
+/// count can't ask for gigabytes of memory
+const MAX_LINES: usize = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+pub enum ObjectError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    InvalidSegmentKind(u8),
+    InvalidString,
+    InvalidImport(u16),
+    NoFiles,
+    TooManyLines(usize),
+    TrailingBytes(usize),
+    Io(String),
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "this is not an rpu object file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "this object file is version {version}, but only \
+                versions up to {VERSION} are supported"
+            ),
+            Self::Truncated => write!(f, "this object file ends early"),
+            Self::InvalidSegmentKind(kind) => {
+                write!(f, "there is no such segment kind as {kind}")
+            },
+            Self::InvalidString => {
+                write!(f, "this object file contains a garbled name")
+            },
+            Self::InvalidImport(index) => write!(
+                f,
+                "this object file refers to import {index}, which isn't \
+                there"
+            ),
+            Self::NoFiles => {
+                write!(f, "this object file doesn't name a source file")
+            },
+            Self::TooManyLines(count) => write!(
+                f,
+                "this object file claims {count} lines of source, but \
+                there can be at most {MAX_LINES}"
+            ),
+            Self::TrailingBytes(count) => write!(
+                f,
+                "this object file has {count} bytes too many"
+            ),
+            Self::Io(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Does `bytes` look like an object file (as opposed to source code)?
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn segment_kind(kind: SegmentKind) -> u8 {
+    match kind {
+        SegmentKind::Code => 0,
+        SegmentKind::Data => 1,
+        SegmentKind::Reserved => 2,
+    }
+}
+
+fn try_segment_kind(byte: u8) -> Result<SegmentKind, ObjectError> {
+    match byte {
+        0 => Ok(SegmentKind::Code),
+        1 => Ok(SegmentKind::Data),
+        2 => Ok(SegmentKind::Reserved),
+        _ => Err(ObjectError::InvalidSegmentKind(byte)),
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn count(&mut self, count: usize) {
+        self.u16(count as u16);
+    }
+
+    fn run(&mut self, bytes: &[u8]) {
+        self.count(bytes.len());
+        self.bytes.extend(bytes);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.run(string.as_bytes());
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, count: usize) -> Result<&'b [u8], ObjectError> {
+        if self.bytes.len() < count {
+            return Err(ObjectError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ObjectError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn count(&mut self) -> Result<usize, ObjectError> {
+        Ok(self.u16()?.into())
+    }
+
+    fn run(&mut self) -> Result<&'b [u8], ObjectError> {
+        let count = self.count()?;
+        self.take(count)
+    }
+
+    fn string(&mut self) -> Result<String, ObjectError> {
+        let bytes = self.run()?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| ObjectError::InvalidString)
+    }
+}
+
+impl Program {
+    /// Encode everything but the source code itself
+    pub fn to_object(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.bytes.extend(MAGIC);
+        writer.u16(VERSION);
+        writer.u16(self.entry);
+
+        writer.count(self.segments.len());
+        for segment in &self.segments {
+            writer.u8(segment_kind(segment.kind));
+            writer.u16(segment.start);
+            writer.run(&segment.bytes);
+        }
+
+        writer.count(self.files.len());
+        for file in &self.files {
+            writer.string(&file.name);
+            writer.u32(file.lines.len() as u32);
+        }
+
+        // Sorted, so that assembling the same source twice gives the
+        // same object file
+        let mut labels: Vec<(&String, &u16)> = self.labels.iter().collect();
+        labels.sort();
+        writer.count(labels.len());
+        for (label, address) in labels {
+            writer.string(label);
+            writer.u16(*address);
+        }
+
+        let mut lines: Vec<(&u16, &usize)> = self.source_addrs.iter()
+            .collect();
+        lines.sort();
+        writer.count(lines.len());
+        for (address, line) in lines {
+            writer.u16(*address);
+            writer.u32(*line as u32);
+        }
+
+        writer.u8(self.relocatable.into());
+        for names in [&self.exports, &self.imports] {
+            writer.count(names.len());
+            for name in names {
+                writer.string(name);
+            }
+        }
+        writer.count(self.relocations.len());
+        for relocation in &self.relocations {
+            let symbol = relocation.symbol.as_ref()
+                .and_then(|symbol| {
+                    self.imports.iter().position(|import| import == symbol)
+                })
+                .map_or(0, |index| index + 1);
+            writer.u16(relocation.address);
+            writer.u16(symbol as u16);
+        }
+
+        writer.bytes
+    }
+
+    /// Decode an object file. Since the source code isn't in there,
+    /// every line of `source_lines` comes out empty.
+    pub fn from_object(bytes: &[u8]) -> Result<Self, ObjectError> {
+        if !is_object(bytes) {
+            return Err(ObjectError::BadMagic);
+        }
+        let mut reader = Reader { bytes: &bytes[MAGIC.len()..] };
+        let version = reader.u16()?;
+        if version == 0 || version > VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let entry = reader.u16()?;
+
+        let mut segments = vec![];
+        for _ in 0..reader.count()? {
+            let kind = try_segment_kind(reader.u8()?)?;
+            let start = reader.u16()?;
+            let bytes = reader.run()?.to_vec();
+            segments.push(Segment { kind, start, bytes });
+        }
+
+        let mut files = vec![];
+        let mut line_count: usize = 0;
+        for _ in 0..reader.count()? {
+            let name = reader.string()?;
+            let first = line_count;
+            line_count = line_count.saturating_add(reader.u32()? as usize);
+            if line_count > MAX_LINES {
+                return Err(ObjectError::TooManyLines(line_count));
+            }
+            files.push(SourceFile { name, lines: first..line_count });
+        }
+
+        // Every program has at least one file to show in the code window
+        if files.is_empty() {
+            return Err(ObjectError::NoFiles);
+        }
+
+        let mut labels = HashMap::new();
+        for _ in 0..reader.count()? {
+            let label = reader.string()?;
+            labels.insert(label, reader.u16()?);
+        }
+
+        let mut source_addrs = HashMap::new();
+        for _ in 0..reader.count()? {
+            let address = reader.u16()?;
+            source_addrs.insert(address, reader.u32()? as usize);
+        }
+
+        let mut relocatable = false;
+        let mut exports = vec![];
+        let mut imports = vec![];
+        let mut relocations = vec![];
+        if version >= 2 {
+            relocatable = reader.u8()? != 0;
+            for names in [&mut exports, &mut imports] {
+                for _ in 0..reader.count()? {
+                    names.push(reader.string()?);
+                }
+            }
+            for _ in 0..reader.count()? {
+                let address = reader.u16()?;
+                let symbol = match reader.u16()? {
+                    0 => None,
+                    index => Some(
+                        imports.get(usize::from(index) - 1)
+                            .ok_or(ObjectError::InvalidImport(index))?
+                            .to_string()
+                    ),
+                };
+                relocations.push(Relocation { address, symbol });
+            }
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(ObjectError::TrailingBytes(reader.bytes.len()));
+        }
+        Ok(Self {
+            segments,
+            entry,
+            source_lines: vec![String::new(); line_count],
+            source_addrs,
+            labels,
+            files,
+            placements: vec![],
+            references: HashMap::new(),
+            relocatable,
+            exports,
+            imports,
+            relocations,
+        })
+    }
+
+    /// Read an object file from disk. If the source files it was
+    /// assembled from happen to be lying around (and still have the
+    /// same number of lines), show their code too.
+    pub fn read_object(path: &Path) -> Result<Self, ObjectError> {
+        let bytes = fs::read(path)
+            .map_err(|e| ObjectError::Io(e.to_string()))?;
+        let mut program = Self::from_object(&bytes)?;
+        for file in &program.files {
+            let Ok(text) = fs::read_to_string(&file.name) else {
+                continue;
+            };
+            let lines: Vec<&str> = text.lines().collect();
+            if lines.len() != file.lines.len() {
+                continue;
+            }
+            for (line, text) in file.lines.clone().zip(lines) {
+                program.source_lines[line] = text.to_string();
+            }
+        }
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::Options;
+
+    fn program() -> Program {
+        let source = [
+            "put .TABLE gp0 .START",
+            "halt",
+            ".org 32",
+            ".TABLE",
+            ".word 7, 8",
+            ".org 224",
+            ".space 32",
+        ];
+        Program::try_compile(&source.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = program();
+        let object = program.to_object();
+        assert!(is_object(&object));
+
+        let loaded = Program::from_object(&object).unwrap();
+        assert_eq!(loaded.segments, program.segments);
+        assert_eq!(loaded.entry, program.entry);
+        assert_eq!(loaded.labels, program.labels);
+        assert_eq!(loaded.source_addrs, program.source_addrs);
+        assert_eq!(loaded.files, program.files);
+        assert_eq!(loaded.source_lines.len(), program.source_lines.len());
+        assert_eq!(loaded.to_object(), object);
+    }
+
+    #[test]
+    fn test_header() {
+        let object = program().to_object();
+        assert_eq!(&object[0..4], b"RPUO");
+        assert_eq!(object[4..6], [2, 0]);
+        assert_eq!(object[6..8], [0, 0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let object = program().to_object();
+
+        let error = Program::from_object(b"put 1 gp0").err();
+        assert_eq!(error, Some(ObjectError::BadMagic));
+
+        let mut newer = object.clone();
+        newer[4] = 3;
+        let error = Program::from_object(&newer).err();
+        assert_eq!(error, Some(ObjectError::UnsupportedVersion(3)));
+
+        let error = Program::from_object(&object[..object.len() - 1]).err();
+        assert_eq!(error, Some(ObjectError::Truncated));
+
+        let mut longer = object.clone();
+        longer.push(0);
+        let error = Program::from_object(&longer).err();
+        assert_eq!(error, Some(ObjectError::TrailingBytes(1)));
+
+        let mut garbled = object.clone();
+        garbled[10] = 9;
+        let error = Program::from_object(&garbled).err();
+        assert_eq!(error, Some(ObjectError::InvalidSegmentKind(9)));
+
+        let mut garbled = object.clone();
+        let name = object.windows(8)
+            .position(|window| window == b"<source>")
+            .unwrap();
+        garbled[name + 8..name + 12].copy_from_slice(&[0xff; 4]);
+        let error = Program::from_object(&garbled).err();
+        let expected = ObjectError::TooManyLines(u32::MAX as usize);
+        assert_eq!(error, Some(expected));
+
+        let mut nameless = program();
+        nameless.files.clear();
+        let error = Program::from_object(&nameless.to_object()).err();
+        assert_eq!(error, Some(ObjectError::NoFiles));
+    }
+
+    fn golden_object(version: u8) -> Vec<u8> {
+        let mut expected = vec![];
+        expected.extend(b"RPUO");
+        expected.extend([version, 0, 0, 0]);
+        expected.extend([1, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        expected.extend([1, 0, 8, 0]);
+        expected.extend(b"<source>");
+        expected.extend([1, 0, 0, 0]);
+        expected.extend([0, 0]);
+        expected.extend([1, 0, 0, 0, 0, 0, 0, 0]);
+        expected
+    }
+
+    #[test]
+    fn test_golden_object() {
+        let program = Program::try_compile("halt").unwrap();
+        let mut expected = golden_object(2);
+        expected.extend([0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(program.to_object(), expected);
+
+        // Version 1 had no linking information
+        let program = Program::from_object(&golden_object(1)).unwrap();
+        assert!(!program.relocatable);
+        assert_eq!(program.segments[0].bytes, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let source = [
+            ".global .START",
+            ".extern .PRINT",
+            "put .PRINT gp0 .START",
+            "put .START+4 gp1",
+        ];
+        let options = Options { relocatable: true, ..Options::default() };
+        let program = Program::try_compile_with(&source.join("\n"), &options)
+            .unwrap();
+        let loaded = Program::from_object(&program.to_object()).unwrap();
+        assert!(loaded.relocatable);
+        assert_eq!(loaded.exports, [".START"]);
+        assert_eq!(loaded.imports, [".PRINT"]);
+        assert_eq!(loaded.relocations, program.relocations);
+        assert_eq!(loaded.relocations.len(), 2);
+
+        let mut garbled = program.to_object();
+        let last = garbled.len() - 2;
+        garbled[last] = 9;
+        let error = Program::from_object(&garbled).err();
+        assert_eq!(error, Some(ObjectError::InvalidImport(9)));
+    }
+}
@@ -1,23 +1,180 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
 use std::mem::size_of;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
 
+use crate::core::RAM;
 use crate::instructions::Instruction;
+use crate::instructions::InstructionName;
+use crate::expressions;
+use crate::includes;
 use crate::instructions;
+use crate::macros;
+use crate::pseudo::PseudoName;
+use crate::registers::RegisterName;
+use crate::registers;
 
+#[derive(Default)]
 pub struct Program {
-    instructions: Vec<Instruction>,
+    /// The pieces of memory that this program fills in, in the
+    /// order they appear in the source
+    pub segments: Vec<Segment>,
+
+    /// Where execution begins: the start of the first code segment
+    pub entry: u16,
+
+    /// Every line of every file that went into this program: the
+    /// main file first, followed by each included file
     pub source_lines: Vec<String>,
+
+    /// Maps instruction addresses to indices in `source_lines`
     pub source_addrs: HashMap<u16, usize>,
+
+    /// The address of every label, local ones under their full name
+    pub labels: HashMap<String, u16>,
+
+    /// Which of the `source_lines` belong to which file
+    pub files: Vec<SourceFile>,
+
+    /// Where the bytes from each line ended up, in the order they
+    /// were assembled. Only known when assembling from source.
+    pub placements: Vec<Placement>,
+
+    /// Where each label was defined and used. Only known when
+    /// assembling from source.
+    pub references: HashMap<String, References>,
+
+    /// Was this assembled to be linked with other programs? If not,
+    /// it only works at the addresses it was assembled for.
+    pub relocatable: bool,
+
+    /// Labels that other programs may use, from `.global`
+    pub exports: Vec<String>,
+
+    /// Labels that some other program has to define, from `.extern`
+    pub imports: Vec<String>,
+
+    /// Every place in a relocatable program that holds the address
+    /// of a label, and so has to change when the program is moved
+    pub relocations: Vec<Relocation>,
 }
 
-fn skippable(line: &str) -> bool {
-    line.starts_with("#") 
-        || line.starts_with(";")
-        || line.len() == 0
+/// A 16-bit value at `address` that holds the address of a label.
+/// `symbol` is the imported label it depends on, or `None` if it
+/// depends on one of the program's own labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub address: u16,
+    pub symbol: Option<String>,
 }
 
-fn tokenize(line: &str) -> Vec<String> {
-    line.split_whitespace()
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    /// Instructions
+    Code,
+
+    /// Values from `.word`
+    Data,
+
+    /// Space set aside with `.space`, which starts out zeroed
+    Reserved,
+}
+
+/// A run of bytes that belongs at a particular address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub start: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    /// The address just past the end of this segment
+    pub fn end(&self) -> usize {
+        usize::from(self.start) + self.bytes.len()
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        usize::from(self.start)..self.end()
+    }
+
+    fn overlaps(&self, other: &Segment) -> bool {
+        let (a, b) = (self.range(), other.range());
+        !a.is_empty() && !b.is_empty()
+            && a.start < b.end && b.start < a.end
+    }
+}
+
+/// The indices of the first pair of segments that claim the same
+/// memory, earlier one first
+fn find_overlap(segments: &[Segment]) -> Option<(usize, usize)> {
+    segments.iter().enumerate().find_map(|(i, segment)| {
+        segments[..i].iter()
+            .position(|earlier| earlier.overlaps(segment))
+            .map(|earlier| (earlier, i))
+    })
+}
+
+/// The addresses that a line of source code was assembled into
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    /// An index into `Program::source_lines`
+    pub line: usize,
+    pub addresses: Range<usize>,
+}
+
+/// The lines (indices into `Program::source_lines`) that mention a
+/// label
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct References {
+    pub defined: usize,
+    pub used: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub name: String,
+    pub lines: Range<usize>,
+}
+
+/// Settings that affect how source code is assembled
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Where to look for `.include`d files that aren't found next
+    /// to the file including them
+    pub include_paths: Vec<PathBuf>,
+
+    /// Constants defined before the source is read, as if by `.equ`
+    pub defines: Vec<(String, String)>,
+
+    /// Assemble for the linker: allow `.extern`, and keep track of
+    /// every place that holds the address of a label
+    pub relocatable: bool,
+}
+
+/// A line of source code on its way through the assembler. `line`
+/// is an index into `Program::source_lines`, and is shared by every
+/// line that a macro expands into.
+pub(crate) struct SourceLine {
+    pub line: usize,
+    pub text: String,
+}
+
+/// Everything after a `#` or `;` is a comment
+pub(crate) fn strip_comment(line: &str) -> &str {
+    match line.find(['#', ';']) {
+        Some(n) => &line[..n],
+        None => line,
+    }
+}
+
+pub(crate) fn tokenize(line: &str) -> Vec<String> {
+    strip_comment(line)
+        .split_whitespace()
         .map(|s| s.to_string())
         .collect()
 }
@@ -25,9 +182,190 @@ fn tokenize(line: &str) -> Vec<String> {
 #[derive(Debug)]
 pub enum CompilationError {
     InstructionParseError(instructions::ParseError),
+    RegisterParseError(registers::ParseError),
     UndefinedLabel(String),
+    UndefinedLocal { label: String, scope: String },
+    AmbiguousLabel { label: String, scope: String },
+    UnknownDirective(String),
+    MissingOperand(String),
+    UnexpectedToken(String),
+    InvalidName(String),
+    UndefinedName(String),
+    CircularConstant(String),
+    ExpressionError(expressions::EvaluationError),
+    Redefinition(String),
+    ShadowsRegister(String),
+    ShadowsInstruction(String),
+    ScratchRegister(String),
+    Misaligned(usize),
+    OutOfMemory(usize),
+    Overlap(u16),
+    NestedMacro,
+    UnterminatedMacro(String),
+    UnexpectedEndm,
+    RecursiveMacro(String),
+    ArgumentCount { name: String, expected: usize, found: usize },
+    UnexpectedElse,
+    UnexpectedEndif,
+    UnterminatedIf,
+    NotRelocatable(String),
+    NeedsLinking(String),
+    IncludeNotFound(String),
+    IncludeCycle(String),
+    Io(String),
+
+    /// Wraps any of the above with the file and (1-based) line
+    /// number that caused it
+    At { file: String, line: usize, error: Box<CompilationError> },
 }
 
+impl CompilationError {
+    /// Attach a (0-based) line number to an error, unless it already
+    /// knows where it came from
+    pub(crate) fn at(line: usize) -> impl FnOnce(Self) -> Self {
+        move |error| match error {
+            Self::At { .. } => error,
+            _ => Self::At {
+                file: String::new(),
+                line: line + 1,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Describe where a local label lives, for error messages
+    fn scope(scope: &str) -> String {
+        match scope.is_empty() {
+            true => "before the first label".to_string(),
+            false => format!("under {scope}"),
+        }
+    }
+
+    /// Turn an index into `Program::source_lines` into a file name
+    /// and a line number within that file
+    fn locate(self, files: &[SourceFile]) -> Self {
+        let Self::At { line, error, .. } = self else {
+            return self;
+        };
+        match files.iter().find(|f| f.lines.contains(&(line - 1))) {
+            Some(file) => Self::At {
+                file: file.name.to_string(),
+                line: line - file.lines.start,
+                error,
+            },
+            None => Self::At { file: String::new(), line, error },
+        }
+    }
+}
+
+impl fmt::Display for CompilationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InstructionParseError(e) => write!(f, "{e}"),
+            Self::RegisterParseError(e) => write!(f, "{e}"),
+            Self::UndefinedLabel(label) => {
+                write!(f, "the label {label} is never defined")
+            },
+            Self::UndefinedLocal { label, scope } => write!(
+                f,
+                "the label {label} is never defined {}",
+                Self::scope(scope)
+            ),
+            Self::AmbiguousLabel { label, scope } => write!(
+                f,
+                "the label {label} is defined more than once {}",
+                Self::scope(scope)
+            ),
+            Self::UnknownDirective(name) => {
+                write!(f, "there is no such directive as {name}")
+            },
+            Self::MissingOperand(name) => {
+                write!(f, "{name} is missing an operand")
+            },
+            Self::UnexpectedToken(token) => {
+                write!(f, "didn't expect to find {token} here")
+            },
+            Self::InvalidName(name) => {
+                write!(f, "{name} is not a valid name")
+            },
+            Self::UndefinedName(name) => {
+                write!(f, "{name} is never defined")
+            },
+            Self::CircularConstant(name) => {
+                write!(f, "{name} is defined in terms of itself")
+            },
+            Self::ExpressionError(e) => write!(f, "{e}"),
+            Self::Redefinition(name) => {
+                write!(f, "{name} is already defined")
+            },
+            Self::ShadowsRegister(name) => {
+                write!(f, "{name} is the name of a register")
+            },
+            Self::ShadowsInstruction(name) => {
+                write!(f, "{name} is the name of an instruction")
+            },
+            Self::ScratchRegister(name) => write!(
+                f,
+                "{name} needs {:?} for itself, so it can't be an operand",
+                crate::pseudo::SCRATCH
+            ),
+            Self::Misaligned(address) => write!(
+                f,
+                "instructions must start at a multiple of 4, not {address}"
+            ),
+            Self::OutOfMemory(address) => write!(
+                f,
+                "this runs past the end of memory (at {address}, \
+                but there are only {RAM} bytes)"
+            ),
+            Self::Overlap(start) => {
+                write!(f, "this overlaps what is already at {start}")
+            },
+            Self::NestedMacro => {
+                write!(f, "macros cannot be defined inside other macros")
+            },
+            Self::UnterminatedMacro(name) => {
+                write!(f, "the macro {name} has no .endm")
+            },
+            Self::UnexpectedEndm => {
+                write!(f, ".endm without a .macro")
+            },
+            Self::RecursiveMacro(name) => {
+                write!(f, "the macro {name} never stops expanding")
+            },
+            Self::ArgumentCount { name, expected, found } => write!(
+                f,
+                "{name} takes {expected} arguments, but was given {found}"
+            ),
+            Self::UnexpectedElse => write!(f, ".else without an .if"),
+            Self::UnexpectedEndif => write!(f, ".endif without an .if"),
+            Self::UnterminatedIf => write!(f, "this .if has no .endif"),
+            Self::NotRelocatable(expression) => write!(
+                f,
+                "{expression} can't be fixed up by the linker, because \
+                it doesn't simply move along with the labels in it"
+            ),
+            Self::NeedsLinking(label) => write!(
+                f,
+                "{label} is defined by another program, so this one has \
+                to be assembled for the linker"
+            ),
+            Self::IncludeNotFound(name) => {
+                write!(f, "can't find {name} to include it")
+            },
+            Self::IncludeCycle(name) => {
+                write!(f, "{name} ends up including itself")
+            },
+            Self::Io(message) => write!(f, "{message}"),
+            Self::At { file, line, error } if file.is_empty() => {
+                write!(f, "line {line}: {error}")
+            },
+            Self::At { file, line, error } => {
+                write!(f, "{file}:{line}: {error}")
+            },
+        }
+    }
+}
 
 impl From<instructions::ParseError> for CompilationError {
     fn from(other: instructions::ParseError) -> Self {
@@ -35,57 +373,963 @@ impl From<instructions::ParseError> for CompilationError {
     }
 }
 
+impl From<expressions::EvaluationError> for CompilationError {
+    fn from(other: expressions::EvaluationError) -> Self {
+        Self::ExpressionError(other)
+    }
+}
+
+impl From<registers::ParseError> for CompilationError {
+    fn from(other: registers::ParseError) -> Self {
+        Self::RegisterParseError(other)
+    }
+}
+
+/// A single line of source code, broken into its parts
+enum Statement {
+    Empty,
+
+    /// A label on a line by itself
+    Label(String),
+
+    /// `.equ NAME expression`
+    Constant(String, String),
+
+    /// `.alias NAME register`
+    Alias(String, String),
+
+    /// `.org expression`: carry on from this address
+    Origin(String),
+
+    /// `.word expression, expression, ...`
+    Words(Vec<String>),
+
+    /// `.space expression`: set aside this many bytes
+    Space(String),
+
+    /// `.global label`: let other programs use this label
+    Global(String),
+
+    /// `.extern label`: this label is defined by another program
+    Extern(String),
+
+    /// An instruction, its operands, and the label (if any) that
+    /// marks its address
+    Instruction {
+        tokens: Vec<String>,
+        label: Option<String>,
+    },
+}
+
+impl Statement {
+    fn try_parse(line: &str) -> Result<Self, CompilationError> {
+        let mut tokens = tokenize(line);
+        if tokens.is_empty() {
+            return Ok(Statement::Empty);
+        }
+
+        if tokens[0].starts_with(".") {
+            return Self::try_parse_directive(tokens);
+        }
+
+        let width = arity(&tokens[0])? + 1;
+        if let Some(index) = value_operand(&tokens[0]) {
+            join_expression(&mut tokens, index + 1);
+        }
+        if tokens.len() < width {
+            return Err(CompilationError::MissingOperand(
+                tokens[0].to_string()
+            ));
+        }
+        let mut extra = tokens.split_off(width).into_iter();
+        let label = extra.next();
+        if let Some(label) = &label {
+            check_label(label)?;
+        }
+        if let Some(token) = extra.next() {
+            return Err(CompilationError::UnexpectedToken(token));
+        }
+        Ok(Statement::Instruction { tokens, label })
+    }
+
+    fn try_parse_directive(tokens: Vec<String>)
+        -> Result<Self, CompilationError>
+    {
+        let expect = |count: usize| {
+            if tokens.len() < count {
+                return Err(CompilationError::MissingOperand(
+                    tokens[0].to_string()
+                ));
+            }
+            if tokens.len() > count {
+                return Err(CompilationError::UnexpectedToken(
+                    tokens[count].to_string()
+                ));
+            }
+            Ok(())
+        };
+        match tokens[0].as_str() {
+            ".equ" => {
+                // The expression may contain spaces
+                let count = tokens.len().max(3);
+                expect(count)?;
+                Ok(Statement::Constant(
+                    tokens[1].to_string(),
+                    tokens[2..].join(" ")
+                ))
+            },
+            ".alias" => {
+                expect(3)?;
+                Ok(Statement::Alias(
+                    tokens[1].to_string(),
+                    tokens[2].to_string()
+                ))
+            },
+            ".org" => {
+                expect(tokens.len().max(2))?;
+                Ok(Statement::Origin(tokens[1..].join(" ")))
+            },
+            ".space" => {
+                expect(tokens.len().max(2))?;
+                Ok(Statement::Space(tokens[1..].join(" ")))
+            },
+            ".global" | ".extern" => {
+                expect(2)?;
+                let label = tokens[1].to_string();
+                check_label(&label)?;
+                if is_local(&label) {
+                    return Err(CompilationError::InvalidName(label));
+                }
+                match tokens[0].as_str() {
+                    ".global" => Ok(Statement::Global(label)),
+                    _ => Ok(Statement::Extern(label)),
+                }
+            },
+            ".word" => {
+                expect(tokens.len().max(2))?;
+                let values = tokens[1..].join(" ")
+                    .split(',')
+                    .map(|value| value.trim().to_string())
+                    .collect();
+                Ok(Statement::Words(values))
+            },
+            _ if tokens.len() == 1 => {
+                check_label(&tokens[0])?;
+                Ok(Statement::Label(tokens[0].to_string()))
+            },
+            _ => Err(CompilationError::UnknownDirective(
+                tokens[0].to_string()
+            ))
+        }
+    }
+}
+
+/// The number of operands taken by an instruction or a
+/// pseudo-instruction
+pub(crate) fn arity(name: &str) -> Result<usize, CompilationError> {
+    match PseudoName::try_parse(name) {
+        Some(pseudo) => Ok(pseudo.arity()),
+        None => Ok(InstructionName::try_parse(name)?.arity()),
+    }
+}
+
+/// Which operand, if any, is a value rather than a register
+fn value_operand(name: &str) -> Option<usize> {
+    match PseudoName::try_parse(name) {
+        Some(PseudoName::jmp) => Some(0),
+        Some(PseudoName::jz) => Some(1),
+        Some(_) => None,
+        None => (name == "put").then_some(0),
+    }
+}
+
+/// An expression with spaces in it, like `.END - .START`, is split up
+/// by `tokenize`. Put it back together: a space belongs to the
+/// expression if there's an operator or a bracket on either side of it.
+fn join_expression(tokens: &mut Vec<String>, start: usize) {
+    let ends_open = |token: &str| {
+        token.ends_with(|c| "+-*/%&|^<>=!~(".contains(c))
+    };
+    let starts_open = |token: &str| {
+        token.starts_with(|c| "+-*/%&|^<>=!)".contains(c))
+    };
+    while start + 1 < tokens.len()
+        && (ends_open(&tokens[start]) || starts_open(&tokens[start + 1]))
+    {
+        let next = tokens.remove(start + 1);
+        tokens[start] = format!("{} {next}", tokens[start]);
+    }
+}
+
+/// Constants may be defined in terms of other constants, up to a
+/// point. Past this point they are assumed to be going in circles.
+const MAX_DEPTH: usize = 32;
+
+/// Names introduced by `.equ` and `.alias`. They share a single
+/// namespace, and may not be confused with registers or labels.
+#[derive(Default)]
+struct Symbols {
+    /// Local labels are kept under their full name, which includes
+    /// the global label they belong to: `.MULTIPLY..LOOP`
+    labels: HashMap<String, usize>,
+
+    /// Local labels that were defined more than once in the same
+    /// scope, so that references to them can't be trusted
+    ambiguous: HashSet<String>,
+
+    /// The most recent global label, which owns any local labels
+    /// that follow it
+    scope: String,
+
+    /// The line each label was defined on
+    defined: HashMap<String, usize>,
+
+    /// Labels from `.extern`, which are worth 0 until they're linked
+    externs: HashSet<String>,
+
+    /// Each constant's expression, which is evaluated wherever the
+    /// constant is used (so that it can refer to any label)
+    constants: HashMap<String, String>,
+
+    aliases: HashMap<String, RegisterName>,
+}
+
+/// When working out whether a value depends on where labels end up,
+/// the labels that get moved (by `SHIFT`)
+#[derive(Clone, Copy)]
+enum Shift<'s> {
+    Nothing,
+
+    /// The program's own labels
+    Own,
+
+    /// A single imported label
+    Import(&'s str),
+}
+
+const SHIFT: usize = 0x1000;
+
+impl Shift<'_> {
+    fn of(&self, label: &str, imported: bool) -> usize {
+        match self {
+            Self::Own if !imported => SHIFT,
+            Self::Import(import) if *import == label => SHIFT,
+            _ => 0,
+        }
+    }
+}
+
+/// Local labels start with `..`, and belong to the global label
+/// above them
+fn is_local(label: &str) -> bool {
+    label.starts_with("..")
+}
+
+/// Labels are a `.` followed by the same sort of characters that
+/// make up any other name
+fn check_label(label: &str) -> Result<(), CompilationError> {
+    let valid = label.len() > 1
+        && label.starts_with(".")
+        && label.chars().all(expressions::is_name_char);
+    match valid {
+        true => Ok(()),
+        false => Err(CompilationError::InvalidName(label.to_string())),
+    }
+}
+
+/// Names must look like identifiers, and must not be confused with
+/// registers
+pub(crate) fn check_name(name: &str) -> Result<(), CompilationError> {
+    let mut chars = name.chars();
+    let valid = chars.next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(CompilationError::InvalidName(name.to_string()));
+    }
+    if RegisterName::try_parse(name).is_ok() {
+        return Err(CompilationError::ShadowsRegister(name.to_string()));
+    }
+    Ok(())
+}
+
+impl Symbols {
+    fn check_name(&self, name: &str) -> Result<(), CompilationError> {
+        check_name(name)?;
+        if self.constants.contains_key(name)
+            || self.aliases.contains_key(name)
+        {
+            return Err(CompilationError::Redefinition(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &str, expression: &str)
+        -> Result<(), CompilationError>
+    {
+        self.check_name(name)?;
+        self.constants.insert(name.to_string(), expression.to_string());
+        Ok(())
+    }
+
+    fn define_alias(&mut self, name: &str, register: &str)
+        -> Result<(), CompilationError>
+    {
+        self.check_name(name)?;
+        let register = RegisterName::try_parse(register)?;
+        self.aliases.insert(name.to_string(), register);
+        Ok(())
+    }
+
+    /// Record the address of a label. Global labels open a new scope,
+    /// except for the ones that macros make up (`.return@3`), so that
+    /// using a macro doesn't cut a routine in two.
+    fn define_label(&mut self, label: &str, address: usize, line: usize)
+        -> Result<(), CompilationError>
+    {
+        if !is_local(label) && !label.contains('@') {
+            self.scope = label.to_string();
+        }
+        let name = self.qualify(label);
+        if self.labels.contains_key(&name) {
+            // A local label used twice in one scope is only a problem
+            // if something refers to it
+            if !is_local(label) {
+                return Err(CompilationError::Redefinition(name));
+            }
+            self.ambiguous.insert(name);
+            return Ok(());
+        }
+        self.defined.insert(name.to_string(), line);
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    /// The full name of a label, as seen from the current scope
+    fn qualify(&self, label: &str) -> String {
+        match is_local(label) {
+            true => format!("{}{label}", self.scope),
+            false => label.to_string(),
+        }
+    }
+
+    fn label(&self, label: &str) -> Result<usize, CompilationError> {
+        let name = self.qualify(label);
+        let scope = self.scope.to_string();
+        if self.ambiguous.contains(&name) {
+            let label = label.to_string();
+            return Err(CompilationError::AmbiguousLabel { label, scope });
+        }
+        if self.externs.contains(&name) {
+            return Ok(0);
+        }
+        match self.labels.get(&name) {
+            Some(address) => Ok(*address),
+            None if is_local(label) => {
+                let label = label.to_string();
+                Err(CompilationError::UndefinedLocal { label, scope })
+            },
+            None => Err(CompilationError::UndefinedLabel(name)),
+        }
+    }
+
+    /// Work out the value of an expression, which may refer to
+    /// labels and constants
+    fn evaluate(&self, expression: &str) -> Result<u16, CompilationError> {
+        self.evaluate_noting(expression, &mut vec![])
+    }
+
+    /// Like `evaluate`, but adds the full name of every label it
+    /// comes across (even by way of a constant) to `used`
+    fn evaluate_noting(&self, expression: &str, used: &mut Vec<String>)
+        -> Result<u16, CompilationError>
+    {
+        self.evaluate_at(expression, 0, used, Shift::Nothing)
+    }
+
+    /// Where the value of `expression` (stored at `address`) comes
+    /// from, if it has to change when labels move. That's only the
+    /// case if it moves right along with them, like `.TABLE+2` does,
+    /// as opposed to `.END-.START`, which doesn't move at all.
+    fn relocation(&self, expression: &str, address: u16)
+        -> Result<Option<Relocation>, CompilationError>
+    {
+        let mut used = vec![];
+        let value = self.evaluate_at(expression, 0, &mut used, Shift::Nothing)?;
+        let not_relocatable = || {
+            CompilationError::NotRelocatable(expression.to_string())
+        };
+        let moved = |shift| {
+            self.evaluate_at(expression, 0, &mut vec![], shift)
+                .map(|moved| usize::from(moved).wrapping_sub(value.into()))
+                .map_err(|_| not_relocatable())
+        };
+
+        let mut symbol = match moved(Shift::Own)? {
+            0 => None,
+            SHIFT => Some(None),
+            _ => return Err(not_relocatable()),
+        };
+        used.sort();
+        used.dedup();
+        for label in used.iter().filter(|label| self.externs.contains(*label)) {
+            match moved(Shift::Import(label))? {
+                0 => {},
+                SHIFT if symbol.is_none() => {
+                    symbol = Some(Some(label.to_string()));
+                },
+                _ => return Err(not_relocatable()),
+            }
+        }
+        Ok(symbol.map(|symbol| Relocation { address, symbol }))
+    }
+
+    fn evaluate_at(
+        &self,
+        expression: &str,
+        depth: usize,
+        used: &mut Vec<String>,
+        shift: Shift,
+    ) -> Result<u16, CompilationError> {
+        expressions::evaluate(expression, |name| {
+            if name.starts_with(".") {
+                let address = self.label(name)?;
+                let name = self.qualify(name);
+                let imported = self.externs.contains(&name);
+                let address = address + shift.of(&name, imported);
+                used.push(name);
+                return Ok(address as i64);
+            }
+            let Some(expression) = self.constants.get(name) else {
+                return Err(CompilationError::UndefinedName(name.to_string()));
+            };
+            if depth >= MAX_DEPTH {
+                return Err(
+                    CompilationError::CircularConstant(name.to_string())
+                );
+            }
+            let value = self.evaluate_at(expression, depth + 1, used, shift)?;
+            Ok(value.into())
+        })
+    }
+
+    /// Is `name` a constant or an alias?
+    fn is_defined(&self, name: &str) -> bool {
+        self.constants.contains_key(name) || self.aliases.contains_key(name)
+    }
+
+    /// Replace an alias with the name of its register
+    fn register(&self, token: &str) -> String {
+        match self.aliases.get(token) {
+            Some(register) => format!("{register:?}"),
+            None => token.to_string(),
+        }
+    }
+}
+
+/// An `.if` (or `.ifdef`, or `.ifndef`) that hasn't seen its
+/// `.endif` yet
+struct Condition {
+    /// Where the `.if` is, in case it never ends
+    line: usize,
+
+    /// Are lines in the current branch being assembled?
+    taking: bool,
+
+    /// Has any branch been assembled yet? If so, `.else` won't be.
+    taken: bool,
+
+    seen_else: bool,
+}
+
+/// Keeps track of which lines `.if` and friends have left out
+#[derive(Default)]
+struct Conditions {
+    stack: Vec<Condition>,
+}
+
+impl Conditions {
+    fn including(&self) -> bool {
+        self.stack.last().is_none_or(|condition| condition.taking)
+    }
+
+    /// Handle `tokens` if they make up a conditional directive,
+    /// returning whether they did
+    fn directive(&mut self, tokens: &[String], line: usize, symbols: &Symbols)
+        -> Result<bool, CompilationError>
+    {
+        let Some(first) = tokens.first() else {
+            return Ok(false);
+        };
+        let missing = || CompilationError::MissingOperand(first.to_string());
+        let test = match first.as_str() {
+            ".if" | ".ifdef" | ".ifndef" if !self.including() => None,
+            ".if" => {
+                if tokens.len() < 2 {
+                    return Err(missing());
+                }
+                Some(symbols.evaluate(&tokens[1..].join(" "))? != 0)
+            },
+            ".ifdef" | ".ifndef" => {
+                let name = tokens.get(1).ok_or_else(missing)?;
+                if let Some(token) = tokens.get(2) {
+                    return Err(
+                        CompilationError::UnexpectedToken(token.to_string())
+                    );
+                }
+                Some(symbols.is_defined(name) == (first == ".ifdef"))
+            },
+            ".else" => {
+                let condition = self.stack.last_mut()
+                    .filter(|condition| !condition.seen_else)
+                    .ok_or(CompilationError::UnexpectedElse)?;
+                condition.taking = !condition.taken;
+                condition.taken = true;
+                condition.seen_else = true;
+                return Ok(true);
+            },
+            ".endif" => {
+                self.stack.pop().ok_or(CompilationError::UnexpectedEndif)?;
+                return Ok(true);
+            },
+            _ => return Ok(false),
+        };
+
+        // Everything inside a block that is left out is left out,
+        // whatever its own condition says
+        let (taking, taken) = match test {
+            Some(test) => (test, test),
+            None => (false, true),
+        };
+        let seen_else = false;
+        self.stack.push(Condition { line, taking, taken, seen_else });
+        Ok(true)
+    }
+
+    fn finish(&self) -> Result<(), CompilationError> {
+        match self.stack.last() {
+            Some(condition) => Err(CompilationError::at(condition.line)(
+                CompilationError::UnterminatedIf
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lines still to be assembled, with the next one last, so that the
+/// lines brought in by an include or a macro can go ahead of the rest
+#[derive(Default)]
+struct Pending(Vec<SourceLine>);
+
+impl Pending {
+    fn push(&mut self, lines: Vec<SourceLine>) {
+        self.0.extend(lines.into_iter().rev());
+    }
+}
+
+impl Iterator for Pending {
+    type Item = SourceLine;
+
+    fn next(&mut self) -> Option<SourceLine> {
+        self.0.pop()
+    }
+}
+
+/// Gathers assembled bytes into segments as they are produced
+#[derive(Default)]
+struct Layout {
+    segments: Vec<Segment>,
+
+    /// The line that started each segment
+    lines: Vec<usize>,
+
+    placements: Vec<Placement>,
+
+    address: usize,
+
+    /// Set by `.org`, so that whatever comes next starts a new
+    /// segment
+    moved: bool,
+}
+
+impl Layout {
+    fn origin(&mut self, address: u16) {
+        self.address = address.into();
+        self.moved = true;
+    }
+
+    /// Place `bytes` at the current address, returning that address
+    fn emit(&mut self, kind: SegmentKind, bytes: &[u8], line: usize)
+        -> Result<u16, CompilationError>
+    {
+        let start = self.address;
+        let aligned = start.is_multiple_of(size_of::<Instruction>());
+        if kind == SegmentKind::Code && !aligned {
+            return Err(CompilationError::Misaligned(start));
+        }
+        if start + bytes.len() > RAM {
+            return Err(CompilationError::OutOfMemory(start));
+        }
+        match self.segments.last_mut() {
+            Some(last) if !self.moved
+                && last.kind == kind
+                && last.end() == start =>
+            {
+                last.bytes.extend_from_slice(bytes);
+            },
+            _ => {
+                let start = start as u16;
+                let bytes = bytes.to_vec();
+                self.segments.push(Segment { kind, start, bytes });
+                self.lines.push(line);
+            },
+        }
+        let addresses = start..start + bytes.len();
+        match self.placements.last_mut() {
+            Some(last) if last.line == line
+                && last.addresses.end == start =>
+            {
+                last.addresses.end = addresses.end;
+            },
+            _ => self.placements.push(Placement { line, addresses }),
+        }
+        self.moved = false;
+        self.address += bytes.len();
+        Ok(start as u16)
+    }
+
+    /// Make sure no two segments claim the same memory
+    fn check_overlaps(&self) -> Result<(), CompilationError> {
+        match find_overlap(&self.segments) {
+            Some((earlier, later)) => Err(
+                CompilationError::at(self.lines[later])(
+                    CompilationError::Overlap(self.segments[earlier].start)
+                )
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
 impl Program {
+    /// Assemble source code that didn't come from a file. Anything
+    /// it includes is looked for relative to the current directory.
     pub fn try_compile(source: &str) -> Result<Self, CompilationError> {
-        let mut instructions = vec![];
-        let mut source_lines = vec![];
+        Self::try_compile_with(source, &Options::default())
+    }
+
+    pub fn try_compile_with(source: &str, options: &Options)
+        -> Result<Self, CompilationError>
+    {
+        Self::try_compile_from(options, "<source>", None, source)
+    }
+
+    pub fn try_compile_file(path: &Path, options: &Options)
+        -> Result<Self, CompilationError>
+    {
+        let source = fs::read_to_string(path)
+            .map_err(|e| CompilationError::Io(e.to_string()))?;
+        let name = path.display().to_string();
+        Self::try_compile_from(options, &name, Some(path), &source)
+    }
+
+    fn try_compile_from(
+        options: &Options,
+        name: &str,
+        path: Option<&Path>,
+        source: &str,
+    ) -> Result<Self, CompilationError> {
+        let mut loader = includes::Loader::new(options);
+        let lines = loader.load(name, path, source, None);
+        let result = Self::assemble(lines, &mut loader, options);
+        let source_lines = std::mem::take(&mut loader.source_lines);
+        let files = std::mem::take(&mut loader.files);
+        match result {
+            Ok(program) => Ok(Self { source_lines, files, ..program }),
+            Err(error) => Err(error.locate(&files)),
+        }
+    }
+
+    /// Turn source lines into a program, reading whatever they include
+    /// with `loader`, and leaving `source_lines` and `files` for the
+    /// caller to fill in
+    fn assemble(
+        source: Vec<SourceLine>,
+        loader: &mut includes::Loader,
+        options: &Options,
+    ) -> Result<Self, CompilationError> {
+        let mut layout = Layout::default();
         let mut source_addrs = HashMap::new();
-        let mut labels = HashMap::<String,usize>::new();
+        let mut symbols = Symbols::default();
+        for (name, value) in &options.defines {
+            symbols.define_constant(name, value)?;
+        }
+        let mut conditions = Conditions::default();
 
         const WIDTH: usize = size_of::<Instruction>();
 
+        let mut macros = macros::Macros::default();
+        let mut pending = Pending::default();
+        pending.push(source);
+
+        let mut statements = vec![];
         let mut estimated_address = 0;
-        for line in source.lines() {
-            if skippable(line) { continue; }
-
-            let tokens = tokenize(line);
-            let final_token = &tokens[tokens.len() - 1];
-            if final_token.starts_with(".") {
-                if !labels.contains_key(final_token) {
-                    labels.insert(
-                        final_token.to_string(),
-                        estimated_address
-                    );
-                }
+        let mut exports = vec![];
+        let mut imports = vec![];
+        while let Some(SourceLine { line: n, text }) = pending.next() {
+            let tokens = tokenize(&text);
+            if conditions.directive(&tokens, n, &symbols)
+                .map_err(CompilationError::at(n))?
+            {
+                continue;
+            }
+
+            // Lines that are left out don't include or define anything
+            if !conditions.including() {
+                macros::skip(n, &tokens, &mut pending)?;
+                continue;
+            }
+            if tokens.first().is_some_and(|first| first == ".include") {
+                let lines = loader.include(&tokens, n)
+                    .map_err(CompilationError::at(n))?;
+                pending.push(lines);
+                continue;
             }
-            estimated_address += WIDTH;
+            let mut expansion = vec![];
+            if macros.line(n, &tokens, &mut pending, &mut expansion)? {
+                pending.push(expansion);
+                continue;
+            }
+
+            let statement = Statement::try_parse(&text)
+                .map_err(CompilationError::at(n))?;
+            match &statement {
+                Statement::Empty => {},
+                Statement::Label(label) => {
+                    symbols.define_label(label, estimated_address, n)
+                        .map_err(CompilationError::at(n))?;
+                },
+                Statement::Constant(name, value) => {
+                    symbols.define_constant(name, value)
+                        .map_err(CompilationError::at(n))?;
+                },
+                Statement::Alias(name, register) => {
+                    symbols.define_alias(name, register)
+                        .map_err(CompilationError::at(n))?;
+                },
+                Statement::Instruction { tokens, label } => {
+                    if let Some(label) = label {
+                        symbols.define_label(label, estimated_address, n)
+                            .map_err(CompilationError::at(n))?;
+                    }
+                    let count = PseudoName::try_parse(&tokens[0])
+                        .map_or(1, |pseudo| pseudo.width());
+                    estimated_address += WIDTH * count;
+                },
+                Statement::Origin(expression) => {
+                    // Only labels defined above this line are known
+                    estimated_address = symbols.evaluate(expression)
+                        .map_err(CompilationError::at(n))?
+                        .into();
+                },
+                Statement::Words(values) => {
+                    estimated_address += 2 * values.len();
+                },
+                Statement::Space(expression) => {
+                    let size: usize = symbols.evaluate(expression)
+                        .map_err(CompilationError::at(n))?
+                        .into();
+                    estimated_address += size;
+                },
+                Statement::Global(label) => {
+                    exports.push((label.to_string(), n));
+                },
+                Statement::Extern(label) => {
+                    if !options.relocatable {
+                        return Err(CompilationError::at(n)(
+                            CompilationError::NeedsLinking(label.to_string())
+                        ));
+                    }
+                    symbols.externs.insert(label.to_string());
+                    imports.push((label.to_string(), n));
+                },
+            }
+            statements.push((n, symbols.scope.to_string(), statement));
         }
+        conditions.finish()?;
 
-        for (n, line) in source.lines().enumerate() {
-            let address = instructions.len() * WIDTH;
-            source_lines.push(line.to_string());
-            if skippable(line) { continue; }
+        for (label, n) in &imports {
+            if symbols.labels.contains_key(label) {
+                return Err(CompilationError::at(*n)(
+                    CompilationError::Redefinition(label.to_string())
+                ));
+            }
+        }
+        for (label, n) in &exports {
+            if !symbols.labels.contains_key(label) {
+                return Err(CompilationError::at(*n)(
+                    CompilationError::UndefinedLabel(label.to_string())
+                ));
+            }
+        }
+        let mut relocations = vec![];
 
-            let mut tokens = tokenize(line);
-            for token in tokens.iter_mut() {
-                if token.starts_with(".") {
-                    let address = labels.get(token).ok_or(
-                        CompilationError::UndefinedLabel(token.to_string())
-                    )?;
-                    *token = format!("{address}");
+        let mut uses: Vec<(String, usize)> = vec![];
+        for (n, scope, statement) in statements {
+            symbols.scope = scope;
+            let mut used = vec![];
+            let tokens = match statement {
+                Statement::Instruction { tokens, .. } => tokens,
+                Statement::Constant(name, _) => {
+                    // Make sure every constant works out, even if
+                    // nobody uses it
+                    symbols.evaluate(&name)
+                        .map_err(CompilationError::at(n))?;
+                    continue;
+                },
+                Statement::Origin(expression) => {
+                    let address = symbols
+                        .evaluate_noting(&expression, &mut used)
+                        .map_err(CompilationError::at(n))?;
+                    layout.origin(address);
+                    uses.extend(used.into_iter().map(|label| (label, n)));
+                    continue;
+                },
+                Statement::Words(values) => {
+                    let mut bytes = vec![];
+                    let mut pending = vec![];
+                    for value in values {
+                        if options.relocatable {
+                            let offset = bytes.len() as u16;
+                            pending.extend(symbols.relocation(&value, offset)
+                                .map_err(CompilationError::at(n))?);
+                        }
+                        let value = symbols
+                            .evaluate_noting(&value, &mut used)
+                            .map_err(CompilationError::at(n))?;
+                        bytes.extend(value.to_le_bytes());
+                    }
+                    let start = layout.emit(SegmentKind::Data, &bytes, n)
+                        .map_err(CompilationError::at(n))?;
+                    for relocation in pending {
+                        let address = start + relocation.address;
+                        relocations.push(Relocation { address, ..relocation });
+                    }
+                    uses.extend(used.into_iter().map(|label| (label, n)));
+                    continue;
+                },
+                Statement::Space(expression) => {
+                    let size = symbols
+                        .evaluate_noting(&expression, &mut used)
+                        .map_err(CompilationError::at(n))?;
+                    let bytes = vec![0; size.into()];
+                    layout.emit(SegmentKind::Reserved, &bytes, n)
+                        .map_err(CompilationError::at(n))?;
+                    uses.extend(used.into_iter().map(|label| (label, n)));
+                    continue;
+                },
+                _ => continue,
+            };
+            let expansion = match PseudoName::try_parse(&tokens[0]) {
+                Some(pseudo) => {
+                    let operands: Vec<String> = tokens[1..].iter()
+                        .map(|token| symbols.register(token))
+                        .collect();
+                    pseudo.expand(&operands)
+                        .map_err(CompilationError::at(n))?
+                },
+                None => vec![tokens],
+            };
+
+            for tokens in expansion {
+                // The first operand of `put` is a value, and
+                // everything else is a register
+                let name = InstructionName::try_parse(&tokens[0])?;
+                let mut resolved = vec![tokens[0].to_string()];
+                let mut pending = None;
+                for (i, token) in tokens[1..].iter().enumerate() {
+                    let is_value = name == InstructionName::put && i == 0;
+                    if is_value && options.relocatable {
+                        // The value is the second byte of a `put`
+                        pending = symbols.relocation(token, 1)
+                            .map_err(CompilationError::at(n))?;
+                    }
+                    let token = match is_value {
+                        true => symbols.evaluate_noting(token, &mut used)
+                            .map_err(CompilationError::at(n))?
+                            .to_string(),
+                        false => symbols.register(token),
+                    };
+                    resolved.push(token);
+                }
+                let line = resolved.join(" ");
+                let instruction = Instruction::try_from_str(&line)
+                    .map_err(|e| CompilationError::at(n)(e.into()))?;
+                let bytes = instruction.to_u32().to_le_bytes();
+                let address = layout.emit(SegmentKind::Code, &bytes, n)
+                    .map_err(CompilationError::at(n))?;
+                source_addrs.insert(address, n);
+                if let Some(relocation) = pending {
+                    let address = address + relocation.address;
+                    relocations.push(Relocation { address, ..relocation });
                 }
             }
-            let line = tokens.join(" ");
-            let instruction = Instruction::try_from_str(&line)?;
-            instructions.push(instruction);
-            source_addrs.insert(address as u16, n);
+            uses.extend(used.into_iter().map(|label| (label, n)));
+        }
+        layout.check_overlaps()?;
+
+        let mut references: HashMap<String, References> = symbols.defined
+            .into_iter()
+            .map(|(label, defined)| {
+                (label, References { defined, used: vec![] })
+            })
+            .collect();
+        for (label, line) in uses {
+            let Some(references) = references.get_mut(&label) else {
+                continue;
+            };
+            if references.used.last() != Some(&line) {
+                references.used.push(line);
+            }
         }
 
-        Ok(Self{ instructions, source_lines, source_addrs })
+        let segments = layout.segments;
+        let entry = segments.iter()
+            .find(|segment| segment.kind == SegmentKind::Code)
+            .map_or(0, |segment| segment.start);
+        let labels = symbols.labels.into_iter()
+            .map(|(label, address)| (label, address as u16))
+            .collect();
+        Ok(Self {
+            segments,
+            entry,
+            source_lines: vec![],
+            source_addrs,
+            labels,
+            files: vec![],
+            placements: layout.placements,
+            references,
+            relocatable: options.relocatable,
+            exports: exports.into_iter().map(|(label, _)| label).collect(),
+            imports: imports.into_iter().map(|(label, _)| label).collect(),
+            relocations,
+        })
     }
 
+    /// The index in `files` of the file that `line` came from
+    pub fn file_of(&self, line: usize) -> Option<usize> {
+        self.files.iter().position(|file| file.lines.contains(&line))
+    }
+
+    /// The address just past the end of the highest segment
     pub fn size(&self) -> usize {
-        self.instructions.len() * size_of::<Instruction>()
+        self.segments.iter().map(Segment::end).max().unwrap_or(0)
+    }
+
+    /// The first pair of segments (if any) that claim the same memory
+    pub fn overlap(&self) -> Option<(&Segment, &Segment)> {
+        find_overlap(&self.segments)
+            .map(|(a, b)| (&self.segments[a], &self.segments[b]))
     }
 
     pub fn bytes<'p>(&'p self) -> EachByte<'p> {
@@ -93,17 +1337,17 @@ impl Program {
     }
 }
 
+/// Every byte from address 0 up to `Program::size`, as it will
+/// look once loaded. Gaps between segments come out as zeroes.
 pub struct EachByte<'p> {
     program: &'p Program,
-    instruction_number: usize,
-    offset: usize
+    address: usize,
 }
 
 impl<'p> EachByte<'p> {
     fn new(program: &'p Program) -> Self {
-        let instruction_number = 0;
-        let offset = 0;
-        Self{ program, instruction_number, offset }
+        let address = 0;
+        Self{ program, address }
     }
 }
 
@@ -111,24 +1355,19 @@ impl<'p> Iterator for EachByte<'p> {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let program_end = self.program.instructions.len();
-        if self.instruction_number >= program_end {
+        if self.address >= self.program.size() {
             return None;
         }
 
-        let instr = &self.program.instructions[
-            self.instruction_number
-        ];
-        let bytes = instr.to_u32().to_ne_bytes();
-        let result = bytes[self.offset];
-        self.offset += 1;
+        let address = self.address;
+        let result = self.program.segments.iter()
+            .find(|segment| segment.range().contains(&address))
+            .map_or(0, |segment| {
+                segment.bytes[address - usize::from(segment.start)]
+            });
+        self.address += 1;
 
-        if self.offset >= 4 {
-            self.offset = 0;
-            self.instruction_number += 1;
-        }
-
-        return Some(result);
+        Some(result)
     }
 }
 
@@ -273,4 +1512,639 @@ mod tests {
         assert_eq!(*program.source_addrs.get(&4).unwrap(), 2);
         assert_eq!(program.source_addrs.get(&8), None);
     }
+
+    #[test]
+    fn test_examples() {
+        let examples = [
+            include_str!("../examples/01.print_5.s"),
+            include_str!("../examples/02.add_5_7.s"),
+            include_str!("../examples/03.bogus.s"),
+            include_str!("../examples/04.jumping.s"),
+            include_str!("../examples/05.do_nothing.s"),
+            include_str!("../examples/06.display_pc.s"),
+            include_str!("../examples/07.countdown.s"),
+            include_str!("../examples/08.99_bottles_of_beer.s"),
+            include_str!("../examples/09.fibonacci.s"),
+            include_str!("../examples/10.fibonacci_stack.s"),
+            include_str!("../examples/11.double.s"),
+            include_str!("../examples/12.double_macro.s"),
+            include_str!("../examples/14.countdown_pseudo.s"),
+            include_str!("../examples/15.segments.s"),
+        ];
+        for source in examples {
+            Program::try_compile(source).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_label_after_comment() {
+        let source = [
+            "put .END gp0",
+            "halt .END ; all done",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[1], 4);
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = [
+            ".equ SIZE 12",
+            ".equ ALSO_SIZE SIZE",
+            "put SIZE gp0",
+            "put ALSO_SIZE gp1",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(program.size(), 8);
+        assert_eq!(memory[1], 12);
+        assert_eq!(memory[5], 12);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let source = [
+            ".alias counter gp3",
+            "put 1 counter",
+            "add counter counter",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[3], RegisterName::gp3 as u8);
+        assert_eq!(memory[5], RegisterName::gp3 as u8);
+        assert_eq!(memory[6], RegisterName::gp3 as u8);
+    }
+
+    #[test]
+    fn test_redefinition() {
+        let source = [
+            ".equ counter 1",
+            ".alias counter gp0",
+        ];
+        let source = source.join("\n");
+        let error = Program::try_compile(&source).err().unwrap();
+
+        let CompilationError::At { line, error, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert_eq!(line, 2);
+        assert!(matches!(*error, CompilationError::Redefinition(_)));
+    }
+
+    #[test]
+    fn test_label_redefinition() {
+        let source = [
+            "noop .A",
+            "noop",
+            "noop .A",
+        ];
+        let error = Program::try_compile(&source.join("\n")).err().unwrap();
+        assert_eq!(error.to_string(), "<source>:3: .A is already defined");
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let sources = [
+            ".equ gp0 1",
+            ".alias sp gp0",
+        ];
+        for source in sources {
+            let error = Program::try_compile(source).err().unwrap();
+            let CompilationError::At { error, .. } = error else {
+                panic!("{error:?}");
+            };
+            assert!(matches!(*error, CompilationError::ShadowsRegister(_)));
+        }
+    }
+
+    #[test]
+    fn test_bare_labels() {
+        let source = [
+            "put .HEAP gp0",
+            ".HEAP",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(program.size(), 4);
+        assert_eq!(memory[1], 4);
+    }
+
+    #[test]
+    fn test_macro_source_addrs() {
+        let source = [
+            ".macro CALL function",
+            "    put .return gp6",
+            "    push gp6",
+            "    put function gp6",
+            "    jump gp6 zero",
+            "    noop .return",
+            ".endm",
+            "CALL .F",
+            "halt",
+            "pop gp6 .F",
+            "jump gp6 zero",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[1], 16);
+        assert_eq!(memory[9], 24);
+        for addr in [0, 4, 8, 12, 16] {
+            assert_eq!(*program.source_addrs.get(&addr).unwrap(), 7);
+        }
+        assert_eq!(*program.source_addrs.get(&20).unwrap(), 8);
+        assert_eq!(program.source_lines.len(), source.lines().count());
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = [
+            ".equ SIZE 2",
+            ".equ LENGTH (.END - .START) / 4",
+            "put .HEAP+4 gp0 .START",
+            "put 2*SIZE gp1",
+            "put LENGTH gp2",
+            "halt .END",
+            ".HEAP",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[1], 20);
+        assert_eq!(memory[5], 4);
+        assert_eq!(memory[9], 3);
+    }
+
+    #[test]
+    fn test_expressions_with_spaces() {
+        let source = [
+            "put (.END - .START) gp0 .START",
+            "put .END + 4 gp1",
+            "jz gp0 .END - 4",
+            "jmp ( .END ) .SKIP",
+            "halt .END",
+        ];
+        let program = Program::try_compile(&source.join("\n")).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[1], 24);
+        assert_eq!(memory[5], 28);
+        assert_eq!(memory[9], 20);
+        assert_eq!(memory[17], 24);
+        assert_eq!(program.labels[".SKIP"], 16);
+
+        // Spaces only join things up around operators
+        let error = Program::try_compile("put 5 gp0 gp1").err().unwrap();
+        assert_eq!(error.to_string(), "<source>:1: gp1 is not a valid name");
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let sources = [
+            (
+                "put 1-2 gp0",
+                CompilationError::ExpressionError(
+                    expressions::EvaluationError::Negative(-1)
+                )
+            ),
+            (
+                "put 256*256 gp0",
+                CompilationError::ExpressionError(
+                    expressions::EvaluationError::TooBig(65536)
+                )
+            ),
+            (
+                ".equ A B\n.equ B A",
+                CompilationError::CircularConstant("A".to_string())
+            ),
+            (
+                ".equ A NOWHERE",
+                CompilationError::UndefinedName("NOWHERE".to_string())
+            ),
+        ];
+        for (source, expected) in sources {
+            let error = Program::try_compile(source).err().unwrap();
+            let CompilationError::At { line, error, .. } = error else {
+                panic!("{error:?}");
+            };
+            assert_eq!(line, 1);
+            assert_eq!(format!("{error:?}"), format!("{expected:?}"));
+        }
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let source = [
+            "inc gp0 .START",
+            "jz gp0 .END",
+            "jmp .START",
+            "halt .END",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        // 3 + 2 + 2 + 1 instructions
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(program.size(), 32);
+        assert_eq!(memory[13], 28);
+        assert_eq!(memory[21], 0);
+        for (addr, line) in [(0, 0), (8, 0), (12, 1), (24, 2), (28, 3)] {
+            assert_eq!(*program.source_addrs.get(&addr).unwrap(), line);
+        }
+    }
+
+    #[test]
+    fn test_scratch_register() {
+        let source = [
+            ".alias temp gp7",
+            "dec temp",
+        ];
+        let source = source.join("\n");
+        let error = Program::try_compile(&source).err().unwrap();
+
+        let CompilationError::At { line, error, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert_eq!(line, 2);
+        assert!(matches!(*error, CompilationError::ScratchRegister(_)));
+    }
+
+    #[test]
+    fn test_segments() {
+        let source = [
+            "put .TABLE gp0",
+            "halt",
+            ".org 32",
+            ".TABLE",
+            ".word 7, .TABLE+2",
+            ".org 64",
+            "noop",
+            ".org 248",
+            ".space 8",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let layout: Vec<(SegmentKind, u16, usize)> = program.segments.iter()
+            .map(|segment| (segment.kind, segment.start, segment.bytes.len()))
+            .collect();
+        assert_eq!(layout, vec![
+            (SegmentKind::Code, 0, 8),
+            (SegmentKind::Data, 32, 4),
+            (SegmentKind::Code, 64, 4),
+            (SegmentKind::Reserved, 248, 8),
+        ]);
+        assert_eq!(program.entry, 0);
+        assert_eq!(program.size(), 256);
+        assert_eq!(*program.source_addrs.get(&64).unwrap(), 6);
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[1], 32);
+        assert_eq!(memory[8..32], [0; 24]);
+        assert_eq!(memory[32], 7);
+        assert_eq!(memory[34], 34);
+        assert_eq!(memory[64], InstructionName::noop as u8);
+    }
+
+    #[test]
+    fn test_entry() {
+        let source = [
+            ".org 16",
+            ".word 1",
+            ".org 40",
+            "halt",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        assert_eq!(program.entry, 40);
+    }
+
+    #[test]
+    fn test_layout_errors() {
+        let sources = [
+            (
+                ".word 1\nhalt",
+                2,
+                CompilationError::Misaligned(2),
+            ),
+            (
+                "noop\nnoop\n.org 4\n.word 5",
+                4,
+                CompilationError::Overlap(0),
+            ),
+            (
+                ".org 252\nnoop\nnoop",
+                3,
+                CompilationError::OutOfMemory(256),
+            ),
+            (
+                ".org .LATER\nnoop .LATER",
+                1,
+                CompilationError::UndefinedLabel(".LATER".to_string()),
+            ),
+        ];
+        for (source, expected_line, expected) in sources {
+            let error = Program::try_compile(source).err().unwrap();
+            let CompilationError::At { line, error, .. } = error else {
+                panic!("{error:?}");
+            };
+            assert_eq!(line, expected_line, "{source}");
+            assert_eq!(format!("{error:?}"), format!("{expected:?}"));
+        }
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let source = [
+            ".macro SKIP",
+            "    put .over gp6",
+            "    jump gp6 zero",
+            "    .over",
+            ".endm",
+            "noop .ONE",
+            "put ..LOOP gp0 ..LOOP",
+            "SKIP",
+            "put ..LOOP gp0",
+            "noop .TWO",
+            "put ..LOOP gp0",
+            "noop ..LOOP",
+            "put .ONE..LOOP gp0",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[5], 4);
+        assert_eq!(memory[17], 4);
+        assert_eq!(memory[25], 28);
+        assert_eq!(memory[33], 4);
+    }
+
+    #[test]
+    fn test_local_label_errors() {
+        let sources = [
+            (
+                "noop\nput ..NOWHERE gp0\nnoop .MAIN",
+                "the label ..NOWHERE is never defined before the first label",
+            ),
+            (
+                "noop .MAIN\nput ..NOWHERE gp0\nnoop .OTHER\nnoop ..NOWHERE",
+                "the label ..NOWHERE is never defined under .MAIN",
+            ),
+            (
+                "noop .MAIN\nput ..TWICE gp0\nnoop ..TWICE\nnoop ..TWICE",
+                "the label ..TWICE is defined more than once under .MAIN",
+            ),
+        ];
+        for (source, expected) in sources {
+            let error = Program::try_compile(source).err().unwrap();
+            let CompilationError::At { line, error, .. } = error else {
+                panic!("{error:?}");
+            };
+            assert_eq!(line, 2);
+            assert_eq!(format!("{error}"), expected);
+        }
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = [
+            ".equ SIZE 2",
+            ".if SIZE > 1",
+            "put 1 gp0",
+            ".else",
+            "this line is never assembled",
+            ".endif",
+            ".ifdef DEBUG",
+            "  .if SIZE",
+            "  put 2 gp0",
+            "  .endif",
+            ".else",
+            "  .ifndef SIZE",
+            "  put 3 gp0",
+            "  .endif",
+            "put 4 gp0",
+            ".endif",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory.len(), 8);
+        assert_eq!(memory[1], 1);
+        assert_eq!(memory[5], 4);
+        assert_eq!(program.source_lines.len(), 16);
+        assert_eq!(*program.source_addrs.get(&4).unwrap(), 14);
+
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), "1".to_string())],
+            ..Options::default()
+        };
+        let program = Program::try_compile_with(&source, &options).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory.len(), 8);
+        assert_eq!(memory[5], 2);
+    }
+
+    #[test]
+    fn test_conditional_macros() {
+        let source = [
+            ".ifdef DEBUG",
+            ".macro LOG r",
+            "  copy r out",
+            ".endm",
+            ".else",
+            ".macro LOG r",
+            "  noop",
+            ".endm",
+            ".endif",
+            "LOG gp0",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory, Instruction::noop.to_u32().to_le_bytes());
+
+        let options = Options {
+            defines: vec![("DEBUG".to_string(), "1".to_string())],
+            ..Options::default()
+        };
+        let program = Program::try_compile_with(&source, &options).unwrap();
+        let memory: Vec<u8> = program.bytes().collect();
+        let copy = Instruction::copy(RegisterName::gp0, RegisterName::out);
+        assert_eq!(memory, copy.to_u32().to_le_bytes());
+
+        // A definition that is left out still needs its .endm
+        let error = Program::try_compile(".if 0\n.macro LOG\n.endif")
+            .err()
+            .unwrap();
+        let CompilationError::At { line, error, .. } = error else {
+            panic!("{error:?}");
+        };
+        assert_eq!(line, 2);
+        assert!(matches!(*error, CompilationError::UnterminatedMacro(_)));
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let sources = [
+            ("noop\n.else", 2, CompilationError::UnexpectedElse),
+            ("noop\n.endif", 2, CompilationError::UnexpectedEndif),
+            (
+                ".if 1\n.else\n.else\n.endif",
+                3,
+                CompilationError::UnexpectedElse,
+            ),
+            (".if 1\n.if 0\n.endif", 1, CompilationError::UnterminatedIf),
+            (
+                ".if NOWHERE\n.endif",
+                1,
+                CompilationError::UndefinedName("NOWHERE".to_string()),
+            ),
+        ];
+        for (source, expected_line, expected) in sources {
+            let error = Program::try_compile(source).err().unwrap();
+            let CompilationError::At { line, error, .. } = error else {
+                panic!("{error:?}");
+            };
+            assert_eq!(line, expected_line, "{source}");
+            assert_eq!(format!("{error:?}"), format!("{expected:?}"));
+        }
+    }
+
+    #[test]
+    fn test_golden_bytes() {
+        let source = [
+            "put 0x1234 gp1",
+            "add gp0 gp1",
+            "copy ans out",
+            "jump gp2 zero",
+            "read gp3 gp4",
+            "mul gp5 gp6",
+            "noop",
+            "pop gp7",
+            "push sp",
+            "write pc dvc",
+            "sub gp0 gp1",
+            "halt",
+            ".word 0xbeef, 1",
+        ];
+        let source = source.join("\n");
+        let program = Program::try_compile(&source).unwrap();
+
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory, vec![
+            9, 0x34, 0x12, 1,
+            1, 0, 1, 0,
+            2, 8, 10, 0,
+            3, 2, 13, 0,
+            4, 3, 4, 0,
+            5, 5, 6, 0,
+            6, 0, 0, 0,
+            7, 7, 0, 0,
+            8, 12, 0, 0,
+            10, 11, 9, 0,
+            11, 0, 1, 0,
+            0, 0, 0, 0,
+            0xef, 0xbe, 1, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_relocations() {
+        let source = [
+            ".global .START",
+            ".extern .PRINT",
+            ".equ NEXT .TABLE+2",
+            "put .PRINT gp0 .START",
+            "put NEXT gp1",
+            "put .TABLE-.START gp2",
+            "put 7 gp3",
+            "jmp .PRINT+4",
+            ".TABLE",
+            ".word .START, 5, .PRINT",
+        ];
+        let options = Options { relocatable: true, ..Options::default() };
+        let program = Program::try_compile_with(&source.join("\n"), &options)
+            .unwrap();
+        let relocations: Vec<(u16, Option<&str>)> = program.relocations
+            .iter()
+            .map(|r| (r.address, r.symbol.as_deref()))
+            .collect();
+        assert_eq!(relocations, vec![
+            (1, Some(".PRINT")),
+            (5, None),
+            (17, Some(".PRINT")),
+            (24, None),
+            (28, Some(".PRINT")),
+        ]);
+        assert_eq!(program.exports, [".START"]);
+        assert_eq!(program.imports, [".PRINT"]);
+
+        // The imported label is worth 0 until it is linked
+        let memory: Vec<u8> = program.bytes().collect();
+        assert_eq!(memory[16..20], [9, 4, 0, 7]);
+    }
+
+    #[test]
+    fn test_relocation_errors() {
+        let relocatable = Options { relocatable: true, ..Options::default() };
+        let sources = [
+            (
+                ".A\nput .A*2 gp0",
+                &relocatable,
+                2,
+                CompilationError::NotRelocatable(".A*2".to_string()),
+            ),
+            (
+                ".extern .B\n.A\nput .A+.B gp0",
+                &relocatable,
+                3,
+                CompilationError::NotRelocatable(".A+.B".to_string()),
+            ),
+            (
+                ".extern .B\nhalt .B",
+                &relocatable,
+                1,
+                CompilationError::Redefinition(".B".to_string()),
+            ),
+            (
+                ".global .B\nhalt",
+                &relocatable,
+                1,
+                CompilationError::UndefinedLabel(".B".to_string()),
+            ),
+            (
+                "halt\n.extern .B",
+                &Options::default(),
+                2,
+                CompilationError::NeedsLinking(".B".to_string()),
+            ),
+        ];
+        for (source, options, expected_line, expected) in sources {
+            let error = Program::try_compile_with(source, options)
+                .err().unwrap();
+            let CompilationError::At { line, error, .. } = error else {
+                panic!("{error:?}");
+            };
+            assert_eq!(line, expected_line, "{source}");
+            assert_eq!(format!("{error:?}"), format!("{expected:?}"));
+        }
+
+        // Label arithmetic is fine when nothing needs to be moved
+        Program::try_compile(".A\nput .A*2 gp0").unwrap();
+    }
 }
@@ -0,0 +1,152 @@
+use crate::programs::CompilationError;
+use crate::registers::RegisterName;
+
+/// Pseudo-instructions that need a register of their own borrow this
+/// one, so anything you keep in it will be lost.
+pub const SCRATCH: RegisterName = RegisterName::gp7;
+
+/// Instructions that the machine doesn't have, but that the
+/// assembler can build out of ones that it does
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PseudoName {
+    /// `jmp .L`: always jump to `.L`
+    jmp,
+
+    /// `jz x .L`: jump to `.L` if `x` is zero
+    jz,
+
+    /// `inc x`: add 1 to `x`
+    inc,
+
+    /// `dec x`: subtract 1 from `x`
+    dec,
+
+    /// `clr x`: set `x` to zero
+    clr,
+}
+
+impl PseudoName {
+    pub fn try_parse(s: &str) -> Option<Self> {
+        match s {
+            "jmp" => Some(PseudoName::jmp),
+            "jz"  => Some(PseudoName::jz),
+            "inc" => Some(PseudoName::inc),
+            "dec" => Some(PseudoName::dec),
+            "clr" => Some(PseudoName::clr),
+            _ => None,
+        }
+    }
+
+    /// The number of operands this takes in source code
+    pub fn arity(&self) -> usize {
+        match self {
+            PseudoName::jz => 2,
+            _ => 1,
+        }
+    }
+
+    /// The number of real instructions this turns into
+    pub fn width(&self) -> usize {
+        match self {
+            PseudoName::jmp => 2,
+            PseudoName::jz  => 2,
+            PseudoName::inc => 3,
+            PseudoName::dec => 3,
+            PseudoName::clr => 1,
+        }
+    }
+
+    /// Turn this into real instructions, given operands whose
+    /// aliases have already been replaced with register names
+    pub fn expand(&self, operands: &[String])
+        -> Result<Vec<Vec<String>>, CompilationError>
+    {
+        let scratch = format!("{SCRATCH:?}");
+        // Only these use the scratch register alongside an operand
+        let registers = match self {
+            PseudoName::jz | PseudoName::inc | PseudoName::dec => {
+                &operands[..1]
+            },
+            PseudoName::jmp | PseudoName::clr => &[][..],
+        };
+        if registers.contains(&scratch) {
+            return Err(CompilationError::ScratchRegister(format!("{self:?}")));
+        }
+
+        let line = |tokens: &[&str]| -> Vec<String> {
+            tokens.iter().map(|t| t.to_string()).collect()
+        };
+        let lines = match self {
+            PseudoName::jmp => vec![
+                line(&["put", &operands[0], &scratch]),
+                line(&["jump", &scratch, "zero"]),
+            ],
+            PseudoName::jz => vec![
+                line(&["put", &operands[1], &scratch]),
+                line(&["jump", &scratch, &operands[0]]),
+            ],
+            PseudoName::inc => vec![
+                line(&["put", "1", &scratch]),
+                line(&["add", &operands[0], &scratch]),
+                line(&["copy", "ans", &operands[0]]),
+            ],
+            PseudoName::dec => vec![
+                line(&["put", "1", &scratch]),
+                line(&["sub", &operands[0], &scratch]),
+                line(&["copy", "ans", &operands[0]]),
+            ],
+            PseudoName::clr => vec![
+                line(&["put", "0", &operands[0]]),
+            ],
+        };
+        debug_assert_eq!(lines.len(), self.width());
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::programs::Program;
+
+    #[test]
+    fn test_expansions() {
+        let pairs = vec![
+            ("jmp", vec![".L"], "put .L gp7; jump gp7 zero"),
+            ("jz", vec!["gp0", ".L"], "put .L gp7; jump gp7 gp0"),
+            ("inc", vec!["gp1"], "put 1 gp7; add gp1 gp7; copy ans gp1"),
+            ("dec", vec!["gp1"], "put 1 gp7; sub gp1 gp7; copy ans gp1"),
+            ("clr", vec!["gp2"], "put 0 gp2"),
+        ];
+        for (name, operands, expected) in pairs {
+            let name = PseudoName::try_parse(name).unwrap();
+            let operands: Vec<String> = operands.iter()
+                .map(|o| o.to_string())
+                .collect();
+            assert_eq!(operands.len(), name.arity());
+            let lines: Vec<String> = name.expand(&operands).unwrap()
+                .iter()
+                .map(|tokens| tokens.join(" "))
+                .collect();
+            assert_eq!(lines.join("; "), expected);
+        }
+    }
+
+    #[test]
+    fn test_scratch_register() {
+        let operands = vec!["gp7".to_string(), ".L".to_string()];
+        for name in ["jz", "inc", "dec"] {
+            let name = PseudoName::try_parse(name).unwrap();
+            let error = name.expand(&operands).err().unwrap();
+            assert!(matches!(error, CompilationError::ScratchRegister(_)));
+        }
+
+        // clr never touches the scratch register, so it can clear it
+        let name = PseudoName::try_parse("clr").unwrap();
+        let lines = name.expand(&operands[..1]).unwrap();
+        assert_eq!(lines, [["put", "0", "gp7"]]);
+        let program = Program::try_compile("clr gp7").unwrap();
+        assert_eq!(program.size(), 4);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::core::RAM;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(non_snake_case)]
 pub struct RegisterFile {
     pub gp0: u16,
@@ -18,6 +20,12 @@ pub struct RegisterFile {
     pub sp:   u16,
 }
 
+impl Default for RegisterFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RegisterFile {
     pub fn new() -> Self {
         Self {
@@ -132,16 +140,61 @@ pub enum ParseError {
     NoSuchRegisterName(String)
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoSuchRegisterName(name) => {
+                write!(f, "there is no such register as {name}")
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum DecodeError {
     NoSuchRegisterID(u8)
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoSuchRegisterID(id) => {
+                write!(f, "there is no register number {id}")
+            },
+        }
+    }
+}
+
+impl fmt::Display for RegisterName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl FromStr for RegisterName {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_parse(s)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum AccessError {
     PseudoRegister(RegisterName),
 }
 
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PseudoRegister(name) => write!(
+                f,
+                "{name} isn't a real register, so it can't be used here"
+            ),
+        }
+    }
+}
+
 impl RegisterName {
     pub fn try_parse(s: &str) -> Result<Self, ParseError> {
         match s {
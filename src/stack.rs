@@ -0,0 +1,168 @@
+//! Making sense of what's on the stack: which values are addresses
+//! in the program, and which of those were pushed by a `CALL` (see
+//! examples/lib/call.s), so that the calls in progress can be listed.
+use crate::core::Core;
+use crate::core::RAM;
+use crate::instructions::Instruction;
+use crate::programs::Program;
+use crate::registers::RegisterName;
+
+/// A value on the stack
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    /// Where it is in memory
+    pub address: u16,
+    pub value: u16,
+
+    /// Labels whose address is the same as the value, which may or
+    /// may not be a coincidence
+    pub labels: Vec<String>,
+}
+
+/// A call in progress
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    /// Where the return address is on the stack
+    pub address: u16,
+
+    /// The address of the routine that was called, and its label
+    pub routine: u16,
+    pub name: Option<String>,
+
+    /// Where it will return to
+    pub return_address: u16,
+}
+
+/// Labels for an address, sorted so that they don't jump around
+fn labels_at(program: &Program, address: u16) -> Vec<String> {
+    let mut labels: Vec<String> = program.labels.iter()
+        .filter(|(_, value)| **value == address)
+        .map(|(label, _)| label.to_string())
+        .collect();
+    labels.sort();
+    labels
+}
+
+impl Core {
+    /// The values on the stack, from the top (the last one pushed)
+    /// down to the bottom
+    pub fn stack(&self, program: &Program) -> Vec<Entry> {
+        let top = usize::from(self.register_file.sp) + 2;
+        (top..RAM - 1).step_by(2)
+            .map(|address| {
+                let value = u16::from_le_bytes([
+                    self.memory[address],
+                    self.memory[address + 1],
+                ]);
+                let labels = labels_at(program, value);
+                Entry { address: address as u16, value, labels }
+            })
+            .collect()
+    }
+
+    /// The instruction at an address, if the bytes there are one
+    pub fn instruction_at(&self, address: usize) -> Option<Instruction> {
+        let bytes = self.memory.get(address..address + 4)?;
+        let word = u32::from_le_bytes(bytes.try_into().ok()?);
+        Instruction::try_from_u32(word).ok()
+    }
+
+    /// If `value` is where a `CALL` returns to, the address of the
+    /// routine it called. `CALL` ends in `put ROUTINE r` and then
+    /// `jump r zero`, just before the address it pushes.
+    fn called_from(&self, value: u16) -> Option<u16> {
+        let value = usize::from(value);
+        let jump = self.instruction_at(value.checked_sub(4)?)?;
+        let put = self.instruction_at(value.checked_sub(8)?)?;
+        match (put, jump) {
+            (
+                Instruction::put(routine, a),
+                Instruction::jump(b, RegisterName::zero),
+            ) if a == b => Some(routine),
+            _ => None,
+        }
+    }
+
+    /// The calls in progress, innermost first
+    pub fn call_stack(&self, program: &Program) -> Vec<Frame> {
+        self.stack(program).into_iter()
+            .filter_map(|entry| {
+                let routine = self.called_from(entry.value)?;
+                // Local labels and ones made up by macros are no
+                // good as names
+                let name = labels_at(program, routine).into_iter()
+                    .find(|label| {
+                        !label.contains("..") && !label.contains('@')
+                    });
+                Some(Frame {
+                    address: entry.address,
+                    routine,
+                    name,
+                    return_address: entry.value,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Device;
+
+    #[test]
+    fn test_call_stack() {
+        let source = [
+            ".macro CALL function",
+            "    put .return gp7",
+            "    push gp7",
+            "    put function gp7",
+            "    jump gp7 zero",
+            ".return",
+            ".endm",
+            "put 5 gp0",
+            "push gp0",
+            "CALL .F",
+            "halt",
+            "noop .F",
+            "CALL .G",
+            "pop gp7",
+            "jump gp7 zero",
+            "noop .G",
+            "halt",
+        ];
+        let program = Program::try_compile(&source.join("\n")).unwrap();
+        let mut core = Core::new();
+        core.load_program(&program).unwrap();
+        let mut devices: Vec<&mut dyn Device> = vec![];
+        while core.is_running() {
+            core.execute_single_instruction(&mut devices).unwrap();
+        }
+
+        let stack = core.stack(&program);
+        assert_eq!(stack.len(), 3);
+        assert_eq!(stack[0], Entry {
+            address: 250,
+            value: 48,
+            labels: vec![".return@2".to_string()],
+        });
+        assert_eq!(stack[2].value, 5);
+        assert!(stack[2].labels.is_empty());
+
+        let calls = core.call_stack(&program);
+        assert_eq!(calls, vec![
+            Frame {
+                address: 250,
+                routine: 56,
+                name: Some(".G".to_string()),
+                return_address: 48,
+            },
+            Frame {
+                address: 252,
+                routine: 28,
+                name: Some(".F".to_string()),
+                return_address: 24,
+            },
+        ]);
+    }
+}